@@ -1,13 +1,102 @@
 defmodule SuperPerfundo.Quarto.AI do
-  alias SuperPerfundo.Quarto.Board
   use Rustler, otp_app: :super_perfundo, crate: "quarto_ai"
 
-  def choose_position_and_next_piece(_board, _active_piece),
+  @doc """
+  Picks where the AI places `active_piece` and which piece it hands back.
+
+  Returns `{:ok, {position, next_piece}}`, or `{:error, reason}` when the board
+  isn't a playable 16-tuple, e.g. `:wrong_board_size`, `:invalid_piece`,
+  `:duplicate_piece`, `:invalid_active_piece`, `:active_piece_on_board` or
+  `:game_over`.
+
+  `difficulty` is one of `:beginner`, `:intermediate`, `:expert` or `:perfect`.
+  Easier levels think for less time and now and then play a random move
+  instead of the one they found.
+
+  Pass a `seed` to make the search's random choices repeatable, e.g. to replay
+  a bad move. Searches stopped by the time budget rather than the round cap can
+  still differ.
+  """
+  def choose_position_and_next_piece(_board, _active_piece, _difficulty \\ :expert, _seed \\ nil),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Starts a search session for one game. The session keeps the AI's search tree
+  between turns, so pass the same session to every
+  `choose_position_and_next_piece_in_session/5` call of that game.
+
+  After each of the AI's moves the session keeps searching on a background
+  thread while the user thinks, for as long as the AI's own search would take,
+  and the next call picks up the branch of the user's actual reply. Calls with
+  a `seed` don't do this and start from a fresh tree, so they can still be
+  replayed. The background search stops when the session is garbage collected.
+  """
+  def new_session, do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Same as `choose_position_and_next_piece/4`, but picks up the search from where
+  the session's previous move left off. It searches on as many threads, each
+  carrying on with a tree of its own.
+  """
+  def choose_position_and_next_piece_in_session(
+        _session,
+        _board,
+        _active_piece,
+        _difficulty \\ :expert,
+        _seed \\ nil
+      ),
+      do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Runs the same search as `choose_position_and_next_piece/4` and reports on it.
+
+  Returns `{:ok, report}` where `report` is a map with the chosen `:position`
+  and `:next_piece`, the `:outcome` (`:win`, `:draw` or `:loss` once the game is
+  proven, otherwise `nil`), the `:rounds` searched, `:elapsed_ms`, `:tree_size`,
+  the `:seed` that replays the search, and:
+
+    * `:children` - every move tried, most visited first, as maps with
+      `:position`, `:piece`, `:next_piece`, `:visits`, the `:win`, `:draw` and
+      `:loss` fractions from the AI's point of view, and a proven `:outcome`
+    * `:principal_variation` - the line of play the AI expects, starting with
+      its own move, as maps with the `:player` making the move (`:ai` or
+      `:user`), `:position`, `:piece` and `:next_piece`
+
+  Errors are the same as for `choose_position_and_next_piece/4`.
+  """
+  def search(_board, _active_piece, _difficulty \\ :expert, _seed \\ nil),
     do: :erlang.nif_error(:nif_not_loaded)
 
-  def choose_next_piece do
-    Board.all_pieces_set()
-    |> Enum.take_random(1)
-    |> List.first()
-  end
+  @doc """
+  Same as `search/4`, but picks up the search from where the session's previous
+  move left off.
+  """
+  def search_in_session(_session, _board, _active_piece, _difficulty \\ :expert, _seed \\ nil),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Suggests moves for the human player, who has to place `active_piece`.
+
+  Returns `{:ok, candidates}` with up to `num_candidates` maps, best first, each
+  with `:position`, `:piece`, `:next_piece`, `:visits`, the `:win`, `:draw` and
+  `:loss` fractions from the player's point of view, and a proven `:outcome`.
+  Errors are the same as for `choose_position_and_next_piece/4`.
+
+  The search is as strong as the AI at `difficulty`. When the move is decided
+  without searching, because it wins on the spot or the nearly full board is
+  solved, there is only that one candidate, with no `:visits`, whatever
+  `num_candidates` is.
+  """
+  def suggest_move(_board, _active_piece, _num_candidates \\ 3, _difficulty \\ :expert),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Picks the piece the AI hands over when it has nothing to place, as when it
+  opens the game: the one the user can do least with, unless an easy
+  `difficulty` gives something away.
+
+  Returns `{:ok, piece}`, or `{:error, reason}` as for
+  `choose_position_and_next_piece/4` when the board can't be played on.
+  """
+  def choose_piece(_board, _difficulty \\ :expert), do: :erlang.nif_error(:nif_not_loaded)
 end
@@ -3,6 +3,9 @@ defmodule SuperPerfundoWeb.QuartoLive do
   alias SuperPerfundo.Quarto.{AI, Board, Game}
   alias SuperPerfundoWeb.PieceComponent
 
+  # Casual visitors lost every game against the full strength AI.
+  @difficulty :intermediate
+
   def mount(_params, _session, socket) do
     socket =
       assign(socket,
@@ -12,7 +15,8 @@ defmodule SuperPerfundoWeb.QuartoLive do
         winning_state: nil,
         draw: false,
         game_start: true,
-        chosen_player: Game.choose_player()
+        chosen_player: Game.choose_player(),
+        ai_session: AI.new_session()
       )
 
     {:ok, socket}
@@ -52,14 +56,18 @@ defmodule SuperPerfundoWeb.QuartoLive do
     end
   end
 
-  def handle_info(:ai_start, socket = %{assigns: %{active_piece: nil}}) do
-    {:noreply, assign(socket, active_piece: AI.choose_next_piece(), active_player: :user)}
+  def handle_info(:ai_start, socket = %{assigns: %{board: board, active_piece: nil}}) do
+    {:ok, piece} = AI.choose_piece(board, @difficulty)
+    {:noreply, assign(socket, active_piece: piece, active_player: :user)}
   end
 
-  def handle_info(:ai_start, socket = %{assigns: %{board: board, active_piece: piece}}) do
-    # why does this fix the live view lag?
-    IO.puts("")
-    {position, next_piece} = AI.choose_position_and_next_piece(board, piece)
+  def handle_info(
+        :ai_start,
+        socket = %{assigns: %{board: board, active_piece: piece, ai_session: session}}
+      ) do
+    {:ok, {position, next_piece}} =
+      AI.choose_position_and_next_piece_in_session(session, board, piece, @difficulty)
+
     board = Board.set_piece(board, piece, position)
     winning_state = Board.four_in_a_row?(board)
     draw = is_nil(winning_state) && Board.full?(board)
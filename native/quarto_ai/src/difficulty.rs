@@ -0,0 +1,122 @@
+use crate::mcts::{AgentBuilder, RolloutPolicy, SafeRollout, UniformRollout, WinningRollout};
+use rustler::NifUnitEnum;
+use std::sync::Arc;
+use std::time::Duration;
+
+// How hard the AI tries. Easier levels search less, explore more widely, throw
+// away some of their moves, play their rollouts less carefully and sample the
+// endgame instead of solving it. Rounds and time are upper bounds: late boards
+// finish their rounds long before the time runs out, the opening is cut short
+// by it.
+#[derive(Debug, Clone, Copy, PartialEq, NifUnitEnum)]
+pub enum Difficulty {
+    Beginner,
+    Intermediate,
+    Expert,
+    Perfect,
+}
+
+struct Preset {
+    num_rounds: u32,
+    time_budget: Duration,
+    temperature: f64,
+    blunder_probability: f64,
+    solver_threshold: u32,
+    rollout_policy: Arc<dyn RolloutPolicy>,
+}
+
+impl Difficulty {
+    pub fn agent_builder(self) -> AgentBuilder {
+        let preset = self.preset();
+        AgentBuilder::new(preset.temperature)
+            .num_rounds(preset.num_rounds)
+            .time_budget(preset.time_budget)
+            .blunder_probability(preset.blunder_probability)
+            .solver_threshold(preset.solver_threshold)
+            .shared_rollout_policy(preset.rollout_policy)
+    }
+
+    fn preset(self) -> Preset {
+        match self {
+            Difficulty::Beginner => Preset {
+                num_rounds: 300,
+                time_budget: Duration::from_millis(250),
+                temperature: 2.0,
+                blunder_probability: 0.35,
+                solver_threshold: 0,
+                rollout_policy: Arc::new(UniformRollout),
+            },
+            Difficulty::Intermediate => Preset {
+                num_rounds: 1500,
+                time_budget: Duration::from_millis(500),
+                temperature: 1.3,
+                blunder_probability: 0.1,
+                solver_threshold: 6,
+                rollout_policy: Arc::new(WinningRollout),
+            },
+            Difficulty::Expert => Preset {
+                num_rounds: 3000,
+                time_budget: Duration::from_millis(1000),
+                temperature: 1.0,
+                blunder_probability: 0.0,
+                solver_threshold: 9,
+                rollout_policy: Arc::new(SafeRollout),
+            },
+            // Solving 10 empty squares takes up to half a second; 11 can take several,
+            // past which the solver gives up and the search samples instead.
+            Difficulty::Perfect => Preset {
+                num_rounds: 20000,
+                time_budget: Duration::from_millis(3000),
+                temperature: 1.0,
+                blunder_probability: 0.0,
+                solver_threshold: 10,
+                rollout_policy: Arc::new(SafeRollout),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::Player;
+    use crate::game::{new_board, GameState};
+
+    const LEVELS: [Difficulty; 4] = [
+        Difficulty::Beginner,
+        Difficulty::Intermediate,
+        Difficulty::Expert,
+        Difficulty::Perfect,
+    ];
+
+    #[test]
+    fn each_level_tries_at_least_as_hard_as_the_one_before() {
+        for pair in LEVELS.windows(2) {
+            let (easier, harder) = (pair[0].agent_builder(), pair[1].agent_builder());
+            assert!(easier.num_rounds <= harder.num_rounds);
+            assert!(easier.time_budget <= harder.time_budget);
+            assert!(easier.blunder_probability >= harder.blunder_probability);
+            assert!(easier.solver_threshold <= harder.solver_threshold);
+        }
+    }
+
+    #[test]
+    fn beginners_miss_some_winning_moves() {
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[1] = Some(2);
+        board[2] = Some(4);
+        let game = GameState::new(board, 8, Player::Agent);
+
+        let misses = (0..100)
+            .filter(|&seed| {
+                let agent = Difficulty::Beginner.agent_builder().seed(seed).build();
+                agent.select_move(game.clone()).position != 3
+            })
+            .count();
+        assert!((15..60).contains(&misses), "missed {} wins", misses);
+
+        let expert = Difficulty::Expert.agent_builder().seed(0).build();
+        assert_eq!(expert.select_move(game).position, 3);
+    }
+}
@@ -1,13 +1,15 @@
-use crate::mcts::{AGENT, OPPONENT};
-use std::collections::HashSet;
+use crate::symmetry::Transform;
+use std::fmt;
 
-type Piece = i32;
-type Position = i32;
+pub type Piece = i32;
+pub type Position = i32;
 pub type Board = [Option<Piece>; 16];
-pub type Player = &'static str;
 
-const TWOS_COMPLIMENT_BITMASK: i32 = 0b1111;
-const MATCH_POSITIONS: [[usize; 4]; 10] = [
+const NUM_SQUARES: i32 = 16;
+const ALL_PIECES: u16 = 0xFFFF;
+const FULL_BOARD: u16 = 0xFFFF;
+const PIECE_BITMASK: u64 = 0b1111;
+pub(crate) const MATCH_POSITIONS: [[usize; 4]; 10] = [
     [0, 1, 2, 3],
     [4, 5, 6, 7],
     [8, 9, 10, 11],
@@ -19,6 +21,14 @@ const MATCH_POSITIONS: [[usize; 4]; 10] = [
     [0, 5, 10, 15],
     [3, 6, 9, 12],
 ];
+const MATCH_MASKS: [u16; 10] = match_masks();
+
+// Zobrist keys: one random number per piece on each square, one per active
+// piece, and one for the opponent being on the move. A game hashes to the XOR
+// of the keys that apply to it, so a move only has to XOR in what changed.
+const ZOBRIST_SQUARES: [[u64; 16]; 16] = zobrist_square_keys();
+const ZOBRIST_ACTIVE_PIECE: [u64; 16] = zobrist_active_piece_keys();
+const ZOBRIST_OPPONENT: u64 = splitmix64(u64::MAX).0;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Move {
@@ -27,54 +37,146 @@ pub struct Move {
     pub next_piece: Piece,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+// One of the two decisions in a turn. The player to move places the active
+// piece, then hands the other player the piece they have to place next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    Place(Position),
+    Give(Piece),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Player {
+    Agent,
+    Opponent,
+}
+
+impl Player {
+    pub const ALL: [Player; 2] = [Player::Agent, Player::Opponent];
+
+    pub fn opponent(self) -> Self {
+        match self {
+            Player::Agent => Player::Opponent,
+            Player::Opponent => Player::Agent,
+        }
+    }
+
+    // Position in per-player arrays.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+// Reasons a board handed to us from outside the crate can't be played on.
+#[derive(Debug, PartialEq)]
+pub enum GameError {
+    InvalidPiece,
+    DuplicatePiece,
+    InvalidActivePiece,
+    ActivePieceOnBoard,
+    GameOver,
+}
+
+// The board is packed into bitboards. Square i holds its piece in the i-th nibble
+// of `squares`, and bit i of `occupied` says whether anything is there at all,
+// since piece 0 is a valid nibble. `remaining` has bit p set for every piece that
+// is neither on the board nor the active piece. Between placing a piece and
+// handing over the next one there is no active piece, and the player who placed
+// it is still the one to move.
+#[derive(PartialEq, Clone)]
 pub struct GameState {
-    board: Board,
-    active_piece: Piece,
-    pub current_player: &'static str,
+    squares: u64,
+    occupied: u16,
+    remaining: u16,
+    active_piece: Option<Piece>,
+    pub current_player: Player,
+    hash: u64,
 }
 
 impl GameState {
-    pub fn new(board: Board, active_piece: Piece, current_player: &'static str) -> Self {
+    pub fn new(board: Board, active_piece: Piece, current_player: Player) -> Self {
+        let mut squares = 0;
+        let mut occupied = 0;
+        let mut remaining = ALL_PIECES & !piece_bit(active_piece);
+
+        for (idx, pos) in board.iter().enumerate() {
+            if let Some(piece) = pos {
+                squares |= (*piece as u64) << (idx * 4);
+                occupied |= 1 << idx;
+                remaining &= !piece_bit(*piece);
+            }
+        }
+
         Self {
-            board,
-            active_piece,
+            squares,
+            occupied,
+            remaining,
+            active_piece: Some(active_piece),
             current_player,
+            hash: 0,
         }
+        .rehashed()
     }
 
-    pub fn is_over(&self) -> bool {
-        four_in_a_row(&self.board) || board_is_full(&self.board)
+    // Like `new`, but for boards that can't be trusted to describe a real game.
+    pub fn validated(
+        board: Board,
+        active_piece: Piece,
+        current_player: Player,
+    ) -> Result<Self, GameError> {
+        if !is_piece(active_piece) {
+            return Err(GameError::InvalidActivePiece);
+        }
+
+        let played_pieces = played_pieces(&board)?;
+        if played_pieces & piece_bit(active_piece) > 0 {
+            return Err(GameError::ActivePieceOnBoard);
+        }
+
+        let game = Self::new(board, active_piece, current_player);
+        if game.is_over() {
+            return Err(GameError::GameOver);
+        }
+        Ok(game)
     }
 
-    pub fn legal_moves(&self) -> Vec<Move> {
-        let mut legal_moves = Vec::new();
-        let mut empty_positions = Vec::new();
-        let mut played_pieces = HashSet::from([self.active_piece]);
-
-        for (idx, pos) in self.board.iter().enumerate() {
-            match pos {
-                Some(piece) => {
-                    played_pieces.insert(*piece);
-                }
-                None => empty_positions.push(idx as i32),
-            }
+    // None while the player to move has a piece to hand over rather than place.
+    pub fn active_piece(&self) -> Option<Piece> {
+        self.active_piece
+    }
+
+    // Unpack the bitboards back into the array format used across the NIF boundary.
+    pub fn board(&self) -> Board {
+        let mut board = new_board();
+        for position in bits(self.occupied) {
+            board[position as usize] = Some(self.piece_at(position));
         }
+        board
+    }
 
-        let all_pieces: HashSet<i32> = (0..16).collect(); // optimize
-        let remaining_pieces: Vec<i32> = all_pieces.difference(&played_pieces).copied().collect();
+    pub fn is_over(&self) -> bool {
+        self.has_four_in_a_row() || self.occupied == FULL_BOARD
+    }
+
+    pub fn legal_moves(&self) -> Vec<Move> {
+        let Some(active_piece) = self.active_piece else {
+            return Vec::new();
+        };
+        let num_empty = NUM_SQUARES as usize - self.occupied.count_ones() as usize;
+        let num_pieces = self.remaining.count_ones().max(1) as usize;
+        let mut legal_moves = Vec::with_capacity(num_empty * num_pieces);
 
-        for position in empty_positions {
+        for position in bits(!self.occupied) {
             let mut legal_move = Move {
                 position,
-                piece: self.active_piece,
+                piece: active_piece,
                 next_piece: 0,
             };
 
-            if remaining_pieces.len() == 0 {
+            if self.remaining == 0 {
                 legal_moves.push(legal_move);
             } else {
-                for &remaining_piece in &remaining_pieces {
+                for remaining_piece in bits(self.remaining) {
                     legal_move.next_piece = remaining_piece;
                     legal_moves.push(legal_move.clone());
                 }
@@ -84,116 +186,312 @@ impl GameState {
         legal_moves
     }
 
-    pub fn empty_positions(&self) -> Vec<i32> {
-        let mut empty_positions = Vec::new();
-        for (idx, pos) in self.board.iter().enumerate() {
-            match pos {
-                None => empty_positions.push(idx as i32),
-                _ => (),
-            }
+    // The decisions open to the player to move: every empty square while there
+    // is a piece to place, every remaining piece once it has been placed.
+    pub fn legal_steps(&self) -> Vec<Step> {
+        match self.active_piece {
+            Some(_) => self.empty_positions().map(Step::Place).collect(),
+            None => self.pieces_to_give().map(Step::Give).collect(),
         }
-        empty_positions
     }
 
-    pub fn winning_move(&self) -> Option<Move> {
-        for position in self.empty_positions() {
-            let current_move = Move {
-                position,
-                piece: self.active_piece,
-                next_piece: 0, // doesn't matter
-            };
+    pub fn empty_positions(&self) -> impl Iterator<Item = Position> {
+        bits(!self.occupied)
+    }
+
+    // The pieces that are neither on the board nor waiting to be placed.
+    pub fn pieces_to_give(&self) -> impl Iterator<Item = Piece> {
+        bits(self.remaining)
+    }
 
-            if self.apply_move(&current_move).is_over() {
-                return Some(current_move);
+    // A placement of the active piece that makes four in a row. Filling the last
+    // square without one is a draw, not a win.
+    pub fn winning_move(&self) -> Option<Move> {
+        let active_piece = self.active_piece?;
+        for position in bits(!self.occupied) {
+            if self.place(position).has_four_in_a_row() {
+                return Some(Move {
+                    position,
+                    piece: active_piece,
+                    next_piece: 0, // doesn't matter
+                });
             }
         }
         None
     }
 
+    pub fn num_empty_positions(&self) -> u32 {
+        self.occupied.count_zeros()
+    }
+
+    // Identifies the position regardless of whose turn it is: the pieces on
+    // the board, which squares they are on, and the active piece, or 16 when
+    // there is none.
+    pub fn position_key(&self) -> u128 {
+        let active_piece = self.active_piece.unwrap_or(16);
+        self.squares as u128 | (self.occupied as u128) << 64 | (active_piece as u128) << 80
+    }
+
+    // A whole turn: place the active piece, then hand over the next one.
     pub fn apply_move(&self, the_move: &Move) -> Self {
-        let mut next_board = self.board.clone();
-        next_board[the_move.position as usize] = Some(the_move.piece);
-        GameState::new(next_board, the_move.next_piece, self.next_player())
+        self.place(the_move.position).give(the_move.next_piece)
+    }
+
+    pub fn apply_step(&self, step: Step) -> Self {
+        match step {
+            Step::Place(position) => self.place(position),
+            Step::Give(piece) => self.give(piece),
+        }
+    }
+
+    // Put the active piece on an empty square. The same player moves next, to
+    // hand over a piece.
+    pub fn place(&self, position: Position) -> Self {
+        let piece = self.active_piece.expect("There is no piece to place");
+        Self {
+            squares: self.squares | (piece as u64) << (position * 4),
+            occupied: self.occupied | 1 << position,
+            active_piece: None,
+            hash: self.hash ^ square_key(position, piece) ^ ZOBRIST_ACTIVE_PIECE[piece as usize],
+            ..self.clone()
+        }
+    }
+
+    // Hand the other player the piece they have to place next.
+    pub fn give(&self, piece: Piece) -> Self {
+        Self {
+            remaining: self.remaining & !piece_bit(piece),
+            active_piece: Some(piece),
+            current_player: self.current_player.opponent(),
+            hash: self.hash ^ ZOBRIST_ACTIVE_PIECE[piece as usize] ^ ZOBRIST_OPPONENT,
+            ..self.clone()
+        }
+    }
+
+    // Zobrist hash of the game, kept up to date move by move.
+    pub fn hash(&self) -> u64 {
+        self.hash
     }
 
-    fn next_player(&self) -> Player {
-        match self.current_player {
-            AGENT => OPPONENT,
-            OPPONENT => AGENT,
-            &_ => panic!("Current player is unsupported {}", self.current_player),
+    fn rehashed(mut self) -> Self {
+        self.hash = self
+            .active_piece
+            .map_or(0, |piece| ZOBRIST_ACTIVE_PIECE[piece as usize]);
+        for position in bits(self.occupied) {
+            self.hash ^= square_key(position, self.piece_at(position));
+        }
+        if self.current_player == Player::Opponent {
+            self.hash ^= ZOBRIST_OPPONENT;
         }
+        self
     }
 
+    // Whoever placed the piece that got the game here: the player to move
+    // when they still have a piece to hand over, the other one once they have.
+    pub fn last_player(&self) -> Player {
+        match self.active_piece {
+            Some(_) => self.current_player.opponent(),
+            None => self.current_player,
+        }
+    }
+
+    // Four in a row wins for whoever placed the piece that made it, which is
+    // no longer the player to move. A full board without one is a draw.
     pub fn winner(&self) -> Option<Player> {
-        if !self.is_over() {
-            return None;
+        if self.has_four_in_a_row() {
+            Some(self.last_player())
+        } else {
+            None
         }
-        if four_in_a_row(&self.board) {
-            return Some(self.current_player);
+    }
+
+    // Move the pieces around the board and relabel them as the transform says.
+    pub fn transform(&self, transform: &Transform) -> Self {
+        let mut squares = 0;
+        let mut occupied = 0;
+        for position in bits(self.occupied) {
+            let new_position = transform.position(position);
+            let piece = transform.piece(self.piece_at(position));
+            squares |= (piece as u64) << (new_position * 4);
+            occupied |= 1 << new_position;
         }
-        None
+
+        let mut remaining = 0;
+        for piece in bits(self.remaining) {
+            remaining |= piece_bit(transform.piece(piece));
+        }
+
+        Self {
+            squares,
+            occupied,
+            remaining,
+            active_piece: self.active_piece.map(|piece| transform.piece(piece)),
+            current_player: self.current_player,
+            hash: 0,
+        }
+        .rehashed()
+    }
+
+    // The representative of every position equivalent to this one under the
+    // board symmetries and piece relabellings, with the transform that gets
+    // there. Moves in the canonical game map back through its inverse.
+    pub fn canonical(&self) -> (Self, Transform) {
+        Transform::all()
+            .iter()
+            .map(|transform| (self.transform(transform), *transform))
+            .min_by_key(|(game, _)| game.position_key())
+            .expect("There is always an identity transform")
+    }
+
+    fn piece_at(&self, position: Position) -> Piece {
+        ((self.squares >> (position * 4)) & PIECE_BITMASK) as Piece
+    }
+
+    pub fn has_four_in_a_row(&self) -> bool {
+        MATCH_MASKS
+            .iter()
+            .zip(MATCH_POSITIONS)
+            .any(|(&mask, positions)| self.occupied & mask == mask && self.pieces_match(positions))
+    }
+
+    // Four pieces match when they all share a set bit, or all share an unset bit.
+    fn pieces_match(&self, positions: [usize; 4]) -> bool {
+        let mut shared_set = PIECE_BITMASK;
+        let mut shared_unset = PIECE_BITMASK;
+        for pos in positions {
+            let piece = (self.squares >> (pos * 4)) & PIECE_BITMASK;
+            shared_set &= piece;
+            shared_unset &= !piece;
+        }
+        (shared_set | shared_unset) & PIECE_BITMASK > 0
     }
 }
 
-pub fn new_board() -> Board {
-    [
-        None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-        None,
-    ]
+impl fmt::Debug for GameState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GameState")
+            .field("board", &self.board())
+            .field("active_piece", &self.active_piece)
+            .field("current_player", &self.current_player)
+            .finish()
+    }
 }
 
-fn board_is_full(board: &Board) -> bool {
-    for piece in board {
-        match piece {
-            None => return false,
-            _ => (),
-        }
+// The pieces that could be handed over next on a board that can't be trusted to
+// describe a real game.
+pub fn remaining_pieces(board: &Board) -> Result<Vec<Piece>, GameError> {
+    let played_pieces = played_pieces(board)?;
+    let remaining: Vec<Piece> = bits(ALL_PIECES & !played_pieces).collect();
+    match remaining.first() {
+        Some(&piece) if !GameState::new(*board, piece, Player::Agent).is_over() => Ok(remaining),
+        _ => Err(GameError::GameOver),
     }
-    true
 }
 
-fn four_in_a_row(board: &Board) -> bool {
-    for positions in MATCH_POSITIONS {
-        let mut pieces = Vec::new();
-        for pos in positions {
-            pieces.push(board[pos]);
-        }
-        let left = match_pieces(pieces[0], pieces[1]);
-        let right = match_pieces(pieces[2], pieces[3]);
-        if any_matches(left, right) {
-            let match_zero_two = match_pieces(pieces[0], pieces[2]);
-            if any_matches(match_zero_two, Some(left.unwrap() & right.unwrap())) {
-                return true;
-            }
+fn played_pieces(board: &Board) -> Result<u16, GameError> {
+    let mut played_pieces = 0;
+    for &piece in board.iter().flatten() {
+        if !is_piece(piece) {
+            return Err(GameError::InvalidPiece);
         }
+        if played_pieces & piece_bit(piece) > 0 {
+            return Err(GameError::DuplicatePiece);
+        }
+        played_pieces |= piece_bit(piece);
     }
-    false
+    Ok(played_pieces)
 }
 
-fn match_pieces(left: Option<Piece>, right: Option<Piece>) -> Option<i32> {
-    if left.is_none() {
-        return None;
-    }
-    if right.is_none() {
-        return None;
+pub fn new_board() -> Board {
+    [None; 16]
+}
+
+// Iterate over the indexes of the set bits in a mask.
+fn bits(mask: u16) -> impl Iterator<Item = i32> {
+    let mut mask = mask;
+    std::iter::from_fn(move || {
+        if mask == 0 {
+            return None;
+        }
+        let idx = mask.trailing_zeros() as i32;
+        mask &= mask - 1;
+        Some(idx)
+    })
+}
+
+fn square_key(position: Position, piece: Piece) -> u64 {
+    ZOBRIST_SQUARES[position as usize][piece as usize]
+}
+
+// Fixed seed, so hashes are stable from one run to the next.
+const fn splitmix64(state: u64) -> (u64, u64) {
+    let state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    (z ^ (z >> 31), state)
+}
+
+const fn zobrist_square_keys() -> [[u64; 16]; 16] {
+    let mut keys = [[0; 16]; 16];
+    let mut state = 0;
+    let mut position = 0;
+    while position < 16 {
+        let mut piece = 0;
+        while piece < 16 {
+            let (key, next_state) = splitmix64(state);
+            keys[position][piece] = key;
+            state = next_state;
+            piece += 1;
+        }
+        position += 1;
     }
-    Some((!(left.unwrap() ^ right.unwrap())) & TWOS_COMPLIMENT_BITMASK)
+    keys
 }
 
-fn any_matches(left: Option<Piece>, right: Option<Piece>) -> bool {
-    if left.is_none() {
-        return false;
+const fn zobrist_active_piece_keys() -> [u64; 16] {
+    let mut keys = [0; 16];
+    let mut state = 1 << 32;
+    let mut piece = 0;
+    while piece < 16 {
+        let (key, next_state) = splitmix64(state);
+        keys[piece] = key;
+        state = next_state;
+        piece += 1;
     }
-    if right.is_none() {
-        return false;
+    keys
+}
+
+fn is_piece(piece: Piece) -> bool {
+    (0..16).contains(&piece)
+}
+
+fn piece_bit(piece: Piece) -> u16 {
+    1 << piece
+}
+
+const fn match_masks() -> [u16; 10] {
+    let mut masks = [0; 10];
+    let mut line = 0;
+    while line < MATCH_POSITIONS.len() {
+        let mut idx = 0;
+        while idx < 4 {
+            masks[line] |= 1 << MATCH_POSITIONS[line][idx];
+            idx += 1;
+        }
+        line += 1;
     }
-    left.unwrap() & right.unwrap() > 0
+    masks
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::mcts::{AGENT, OPPONENT};
+    use crate::symmetry::Transform;
+
+    fn four_in_a_row(board: Board) -> bool {
+        GameState::new(board, 0, Player::Agent).has_four_in_a_row()
+    }
 
     fn draw_board() -> Board {
         [
@@ -218,46 +516,348 @@ mod tests {
 
     #[test]
     fn game_winner_is_none_when_neither_side_has_won() {
-        let state = GameState::new(draw_board(), 0, OPPONENT);
+        let state = GameState::new(draw_board(), 0, Player::Opponent);
         assert_eq!(state.winner(), None);
     }
 
     #[test]
-    fn game_winner_is_current_player_with_four_in_a_row() {
+    fn game_winner_is_the_player_who_made_four_in_a_row() {
         let board = [Some(0); 16];
-        let state = GameState::new(board, 0, OPPONENT);
-        assert_eq!(state.winner().unwrap(), OPPONENT);
+        let state = GameState::new(board, 0, Player::Opponent);
+        assert_eq!(state.winner().unwrap(), Player::Agent);
+    }
+
+    #[test]
+    fn game_winner_is_the_player_who_placed_the_last_piece() {
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[1] = Some(1);
+        board[2] = Some(2);
+        let state = GameState::new(board, 3, Player::Opponent);
+        let state = state.apply_move(&Move {
+            position: 3,
+            piece: 3,
+            next_piece: 4,
+        });
+        assert_eq!(state.current_player, Player::Agent);
+        assert_eq!(state.winner(), Some(Player::Opponent));
     }
 
     #[test]
     fn game_winner_returns_none_when_game_is_not_over() {
-        let state = GameState::new(new_board(), 0, OPPONENT);
+        let state = GameState::new(new_board(), 0, Player::Opponent);
         assert_eq!(state.winner(), None);
     }
 
     #[test]
     fn apply_move_returns_updated_game_state() {
-        let state = GameState::new(new_board(), 0, OPPONENT);
+        let state = GameState::new(new_board(), 0, Player::Opponent);
         let new_move = Move {
             position: 1,
-            piece: 2,
+            piece: 0,
             next_piece: 8,
         };
         let new_state = state.apply_move(&new_move);
-        assert_eq!(new_state.board[1].unwrap(), 2);
-        assert_eq!(new_state.active_piece, 8);
-        assert_eq!(new_state.current_player, AGENT);
-        assert_ne!(new_state.board, state.board);
+        assert_eq!(new_state.board()[1].unwrap(), 0);
+        assert_eq!(new_state.active_piece, Some(8));
+        assert_eq!(new_state.current_player, Player::Agent);
+        assert_ne!(new_state.board(), state.board());
+    }
+
+    #[test]
+    fn a_move_is_a_placement_then_a_piece_handed_over() {
+        let state = GameState::new(new_board(), 0, Player::Opponent);
+        let placed = state.place(1);
+        assert_eq!(placed.board()[1], Some(0));
+        assert_eq!(placed.active_piece(), None);
+        assert_eq!(placed.current_player, Player::Opponent);
+        assert_eq!(placed.legal_steps().len(), 15);
+        assert!(placed.legal_moves().is_empty());
+
+        let given = placed.give(8);
+        assert_eq!(given.active_piece(), Some(8));
+        assert_eq!(given.current_player, Player::Agent);
+        assert_eq!(given.legal_steps().len(), 15);
+        assert_eq!(
+            given,
+            state.apply_move(&Move {
+                position: 1,
+                piece: 0,
+                next_piece: 8,
+            })
+        );
+        let fresh = GameState::new(given.board(), 8, Player::Agent);
+        assert_eq!(given.hash(), fresh.hash());
+    }
+
+    #[test]
+    fn placing_the_winning_piece_wins_before_anything_is_handed_over() {
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[1] = Some(2);
+        board[2] = Some(4);
+        let placed = GameState::new(board, 8, Player::Opponent).place(3);
+        assert!(placed.is_over());
+        assert_eq!(placed.winner(), Some(Player::Opponent));
+    }
+
+    #[test]
+    fn placing_and_handing_over_hash_differently() {
+        let state = GameState::new(new_board(), 0, Player::Agent);
+        let placed = state.place(1);
+        assert_ne!(placed.hash(), state.hash());
+        assert_ne!(placed.hash(), placed.give(2).hash());
+        assert_ne!(placed.position_key(), placed.give(2).position_key());
+    }
+
+    #[test]
+    fn validated_accepts_a_playable_game() {
+        let mut board = new_board();
+        board[4] = Some(0);
+        board[9] = Some(15);
+        let state = GameState::validated(board, 7, Player::Agent).unwrap();
+        assert_eq!(state, GameState::new(board, 7, Player::Agent));
+    }
+
+    #[test]
+    fn validated_rejects_pieces_that_do_not_exist() {
+        let mut board = new_board();
+        board[4] = Some(16);
+        assert_eq!(
+            GameState::validated(board, 7, Player::Agent),
+            Err(GameError::InvalidPiece)
+        );
+        board[4] = Some(-1);
+        assert_eq!(
+            GameState::validated(board, 7, Player::Agent),
+            Err(GameError::InvalidPiece)
+        );
+    }
+
+    #[test]
+    fn validated_rejects_pieces_played_twice() {
+        let mut board = new_board();
+        board[4] = Some(3);
+        board[9] = Some(3);
+        assert_eq!(
+            GameState::validated(board, 7, Player::Agent),
+            Err(GameError::DuplicatePiece)
+        );
+    }
+
+    #[test]
+    fn validated_rejects_an_active_piece_that_does_not_exist() {
+        assert_eq!(
+            GameState::validated(new_board(), 16, Player::Agent),
+            Err(GameError::InvalidActivePiece)
+        );
+    }
+
+    #[test]
+    fn validated_rejects_an_active_piece_already_on_the_board() {
+        let mut board = new_board();
+        board[4] = Some(7);
+        assert_eq!(
+            GameState::validated(board, 7, Player::Agent),
+            Err(GameError::ActivePieceOnBoard)
+        );
+    }
+
+    #[test]
+    fn validated_rejects_a_game_that_is_already_won() {
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[1] = Some(2);
+        board[2] = Some(4);
+        board[3] = Some(8);
+        assert_eq!(
+            GameState::validated(board, 15, Player::Agent),
+            Err(GameError::GameOver)
+        );
+    }
+
+    #[test]
+    fn remaining_pieces_lists_the_pieces_not_on_the_board() {
+        let mut board = new_board();
+        board[4] = Some(0);
+        board[9] = Some(15);
+        assert_eq!(remaining_pieces(&board), Ok((1..15).collect()));
+    }
+
+    #[test]
+    fn remaining_pieces_rejects_boards_that_cannot_be_played_on() {
+        let mut board = new_board();
+        board[0] = Some(16);
+        assert_eq!(remaining_pieces(&board), Err(GameError::InvalidPiece));
+
+        board[0] = Some(3);
+        board[1] = Some(3);
+        assert_eq!(remaining_pieces(&board), Err(GameError::DuplicatePiece));
+
+        assert_eq!(remaining_pieces(&draw_board()), Err(GameError::GameOver));
+
+        let mut won_board = new_board();
+        won_board[0] = Some(1);
+        won_board[1] = Some(3);
+        won_board[2] = Some(5);
+        won_board[3] = Some(9);
+        assert_eq!(remaining_pieces(&won_board), Err(GameError::GameOver));
+    }
+
+    #[test]
+    fn winning_move_places_the_active_piece_to_make_four_in_a_row() {
+        let mut board = new_board();
+        board[4] = Some(0);
+        board[5] = Some(2);
+        board[6] = Some(4);
+        let state = GameState::new(board, 8, Player::Agent);
+        assert_eq!(state.winning_move().unwrap().position, 7);
+    }
+
+    #[test]
+    fn winning_move_is_none_when_the_last_square_only_draws() {
+        let mut board = draw_board();
+        let last_piece = board[15].take().unwrap();
+        let state = GameState::new(board, last_piece, Player::Agent);
+        assert_eq!(state.winning_move(), None);
+    }
+
+    #[test]
+    fn position_key_differs_by_board_and_active_piece_only() {
+        let mut board = new_board();
+        board[2] = Some(0);
+        let state = GameState::new(board, 5, Player::Agent);
+        let same_position = GameState::new(board, 5, Player::Opponent);
+        let other_active_piece = GameState::new(board, 6, Player::Agent);
+        let empty_square = GameState::new(new_board(), 5, Player::Agent);
+
+        assert_eq!(state.position_key(), same_position.position_key());
+        assert_ne!(state.position_key(), other_active_piece.position_key());
+        assert_ne!(state.position_key(), empty_square.position_key());
+    }
+
+    #[test]
+    fn canonical_is_the_same_for_every_equivalent_game() {
+        let mut board = new_board();
+        board[0] = Some(3);
+        board[6] = Some(12);
+        board[9] = Some(5);
+        let state = GameState::new(board, 10, Player::Agent);
+        let (canonical, _) = state.canonical();
+
+        for transform in Transform::all().iter().step_by(97) {
+            let (other, _) = state.transform(transform).canonical();
+            assert_eq!(other, canonical);
+        }
+    }
+
+    #[test]
+    fn canonical_treats_every_opening_piece_the_same() {
+        let (canonical, _) = GameState::new(new_board(), 0, Player::Agent).canonical();
+        for piece in 1..16 {
+            assert_eq!(
+                GameState::new(new_board(), piece, Player::Agent)
+                    .canonical()
+                    .0,
+                canonical
+            );
+        }
+    }
+
+    #[test]
+    fn canonical_moves_map_back_to_moves_in_the_original_game() {
+        let mut board = new_board();
+        board[1] = Some(7);
+        board[4] = Some(0);
+        board[15] = Some(9);
+        let state = GameState::new(board, 2, Player::Agent);
+        let (canonical, transform) = state.canonical();
+        assert_eq!(state.transform(&transform), canonical);
+
+        let inverse = transform.inverse();
+        for canonical_move in canonical.legal_moves() {
+            let original_move = inverse.apply_move(&canonical_move);
+            assert!(state.legal_moves().contains(&original_move));
+            assert_eq!(
+                state.apply_move(&original_move).transform(&transform),
+                canonical.apply_move(&canonical_move)
+            );
+        }
+    }
+
+    #[test]
+    fn transform_keeps_four_in_a_row() {
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[5] = Some(2);
+        board[10] = Some(4);
+        board[15] = Some(8);
+        let state = GameState::new(board, 1, Player::Agent);
+        for transform in Transform::all().iter().step_by(101) {
+            assert!(state.transform(transform).has_four_in_a_row());
+        }
+    }
+
+    #[test]
+    fn hash_after_moves_matches_the_hash_of_the_same_game_built_fresh() {
+        let mut state = GameState::new(new_board(), 3, Player::Agent);
+        for (position, next_piece) in [(5, 9), (0, 14), (12, 1), (7, 0)] {
+            let piece = state.active_piece.unwrap();
+            state = state.apply_move(&Move {
+                position,
+                piece,
+                next_piece,
+            });
+            let active_piece = state.active_piece.unwrap();
+            let fresh = GameState::new(state.board(), active_piece, state.current_player);
+            assert_eq!(state.hash(), fresh.hash());
+        }
+    }
+
+    #[test]
+    fn hash_is_the_same_for_transposed_move_orders() {
+        let state = GameState::new(new_board(), 0, Player::Agent);
+        let play = |moves: [(i32, i32, i32); 3]| {
+            moves
+                .iter()
+                .fold(state.clone(), |game, &(position, piece, next_piece)| {
+                    game.apply_move(&Move {
+                        position,
+                        piece,
+                        next_piece,
+                    })
+                })
+        };
+        let first = play([(0, 0, 1), (5, 1, 2), (10, 2, 3)]);
+        let second = play([(0, 0, 2), (10, 2, 1), (5, 1, 3)]);
+        assert_eq!(first, second);
+        assert_eq!(first.hash(), second.hash());
+    }
+
+    #[test]
+    fn hash_differs_by_active_piece_and_player() {
+        let mut board = new_board();
+        board[8] = Some(4);
+        let state = GameState::new(board, 2, Player::Agent);
+        assert_ne!(state.hash(), GameState::new(board, 3, Player::Agent).hash());
+        assert_ne!(
+            state.hash(),
+            GameState::new(board, 2, Player::Opponent).hash()
+        );
+        assert_ne!(
+            state.hash(),
+            GameState::new(new_board(), 2, Player::Agent).hash()
+        );
     }
 
     #[test]
     fn is_over_is_true_when_the_board_is_full() {
         let mut board = new_board();
-        for i in 0..16 {
-            board[i] = Some(i as Piece);
+        for (i, square) in board.iter_mut().enumerate() {
+            *square = Some(i as Piece);
         }
-        let game = GameState::new(board, 0, OPPONENT);
-        assert_eq!(game.is_over(), true);
+        let game = GameState::new(board, 0, Player::Opponent);
+        assert!(game.is_over());
     }
 
     #[test]
@@ -267,14 +867,14 @@ mod tests {
         board[1] = Some(2);
         board[2] = Some(4);
         board[3] = Some(8);
-        let game = GameState::new(board, 15, OPPONENT);
-        assert_eq!(game.is_over(), true);
+        let game = GameState::new(board, 15, Player::Opponent);
+        assert!(game.is_over());
     }
 
     #[test]
     fn is_over_is_false_when_the_board_is_empty() {
-        let game = GameState::new(new_board(), 0, AGENT);
-        assert_eq!(game.is_over(), false);
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        assert!(!game.is_over());
     }
 
     #[test]
@@ -297,14 +897,39 @@ mod tests {
             None,
             None,
         ];
-        let state = GameState::new(board, 15, AGENT);
+        let state = GameState::new(board, 15, Player::Agent);
         let legal_moves = state.legal_moves();
         assert_eq!(legal_moves.len(), 6);
     }
 
+    #[test]
+    fn legal_moves_never_hand_over_played_or_active_pieces() {
+        let mut board = new_board();
+        board[0] = Some(3);
+        board[5] = Some(9);
+        let state = GameState::new(board, 12, Player::Agent);
+        let legal_moves = state.legal_moves();
+        assert_eq!(legal_moves.len(), 14 * 13);
+        assert!(legal_moves
+            .iter()
+            .all(|m| m.piece == 12 && ![3, 9, 12].contains(&m.next_piece)));
+        assert!(legal_moves
+            .iter()
+            .all(|m| m.position != 0 && m.position != 5));
+    }
+
+    #[test]
+    fn board_converts_back_to_the_array_it_was_built_from() {
+        let mut board = draw_board();
+        board[3] = None;
+        board[15] = None;
+        let state = GameState::new(board, 10, Player::Agent);
+        assert_eq!(state.board(), board);
+    }
+
     #[test]
     fn legal_moves_returns_a_vector_of_moves() {
-        let state = GameState::new(new_board(), 0, AGENT);
+        let state = GameState::new(new_board(), 0, Player::Agent);
         let legal_moves = state.legal_moves();
         assert_eq!(legal_moves.len(), 16 * 15);
     }
@@ -316,7 +941,7 @@ mod tests {
         board[1] = Some(3);
         board[2] = Some(5);
         board[3] = Some(9);
-        assert_eq!(four_in_a_row(&board), true);
+        assert!(four_in_a_row(board));
     }
 
     #[test]
@@ -326,7 +951,7 @@ mod tests {
         board[1] = Some(1);
         board[2] = Some(4);
         board[3] = Some(8);
-        assert_eq!(four_in_a_row(&board), true);
+        assert!(four_in_a_row(board));
     }
 
     #[test]
@@ -336,7 +961,7 @@ mod tests {
         board[1] = Some(3);
         board[2] = Some(6);
         board[3] = Some(10);
-        assert_eq!(four_in_a_row(&board), true);
+        assert!(four_in_a_row(board));
     }
 
     #[test]
@@ -346,7 +971,7 @@ mod tests {
         board[1] = Some(1);
         board[2] = Some(2);
         board[3] = Some(8);
-        assert_eq!(four_in_a_row(&board), true);
+        assert!(four_in_a_row(board));
     }
 
     #[test]
@@ -356,7 +981,7 @@ mod tests {
         board[1] = Some(5);
         board[2] = Some(6);
         board[3] = Some(12);
-        assert_eq!(four_in_a_row(&board), true);
+        assert!(four_in_a_row(board));
     }
 
     #[test]
@@ -366,7 +991,7 @@ mod tests {
         board[1] = Some(1);
         board[2] = Some(2);
         board[3] = Some(4);
-        assert_eq!(four_in_a_row(&board), true);
+        assert!(four_in_a_row(board));
     }
 
     #[test]
@@ -376,7 +1001,7 @@ mod tests {
         board[1] = Some(9);
         board[2] = Some(10);
         board[3] = Some(12);
-        assert_eq!(four_in_a_row(&board), true);
+        assert!(four_in_a_row(board));
     }
 
     #[test]
@@ -386,17 +1011,17 @@ mod tests {
         board[1] = Some(10);
         board[2] = Some(9);
         board[3] = Some(4);
-        assert_eq!(four_in_a_row(&board), false);
+        assert!(!four_in_a_row(board));
     }
 
     #[test]
     fn four_in_a_row_returns_false_when_four_pieces_do_not_match() {
         let board = new_board();
-        assert_eq!(four_in_a_row(&board), false)
+        assert!(!four_in_a_row(board));
     }
 
     #[test]
     fn four_in_a_row_returns_false_for_a_draw_board() {
-        assert_eq!(four_in_a_row(&draw_board()), false);
+        assert!(!four_in_a_row(draw_board()));
     }
 }
@@ -1,33 +1,323 @@
+mod difficulty;
 mod game;
 mod mcts;
+mod session;
+mod solver;
+mod symmetry;
 
-use game::{new_board, Board, GameState};
-use mcts::{Agent, AGENT};
-use rustler::{types::tuple::get_tuple, Term};
+use difficulty::Difficulty;
+use game::{new_board, Board, GameError, GameState, Move, Player};
+use mcts::{Agent, ChildReport, SearchReport};
+use rustler::{
+    types::tuple::get_tuple, Atom, Decoder, Encoder, Env, NifMap, NifResult, ResourceArc, Term,
+};
+use session::SearchSession;
+use solver::Outcome;
+use std::thread;
+
+const MAX_SEARCH_THREADS: usize = 4;
+
+mod atoms {
+    rustler::atoms! {
+        nil,
+        not_a_tuple,
+        wrong_board_size,
+        invalid_piece,
+        duplicate_piece,
+        invalid_active_piece,
+        active_piece_on_board,
+        game_over,
+        win,
+        draw,
+        loss,
+        ai,
+        user,
+    }
+}
+
+// The players go by the names quarto_live.ex gives them.
+impl Encoder for Player {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            Player::Agent => atoms::ai(),
+            Player::Opponent => atoms::user(),
+        }
+        .encode(env)
+    }
+}
+
+impl<'a> Decoder<'a> for Player {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        let atom: Atom = term.decode()?;
+        if atom == atoms::ai() {
+            Ok(Player::Agent)
+        } else if atom == atoms::user() {
+            Ok(Player::Opponent)
+        } else {
+            Err(rustler::Error::BadArg)
+        }
+    }
+}
+
+// The SearchReport as an Elixir map. Fractions are from the searching player's
+// point of view.
+#[derive(NifMap)]
+struct ReportMap {
+    position: i32,
+    next_piece: i32,
+    outcome: Option<Atom>,
+    rounds: u32,
+    elapsed_ms: u64,
+    tree_size: usize,
+    seed: u64,
+    children: Vec<ChildMap>,
+    principal_variation: Vec<MoveMap>,
+}
+
+#[derive(NifMap)]
+struct ChildMap {
+    position: i32,
+    piece: i32,
+    next_piece: i32,
+    visits: i32,
+    win: f64,
+    draw: f64,
+    loss: f64,
+    outcome: Option<Atom>,
+}
+
+#[derive(NifMap)]
+struct MoveMap {
+    player: Player,
+    position: i32,
+    piece: i32,
+    next_piece: i32,
+}
+
+// rustler does not support generics currently.
+// A search keeps a scheduler busy for up to a second or more, far past the ~1ms
+// a NIF may run on a normal scheduler, so it goes on a dirty CPU scheduler
+// instead. The seed is nil unless a search needs replaying.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn choose_position_and_next_piece(
+    board: Term,
+    active_piece: Term,
+    difficulty: Difficulty,
+    seed: Option<u64>,
+) -> Result<(i32, i32), Atom> {
+    let game = convert_terms_to_game(board, active_piece, Player::Agent)?;
+    let selected_move = build_agent(difficulty, seed).select_move(game);
+    Ok((selected_move.position, selected_move.next_piece))
+}
+
+#[rustler::resource_impl]
+impl rustler::Resource for SearchSession {}
 
-// rustler does not support generics currently
 #[rustler::nif]
-fn choose_position_and_next_piece(board: Term, active_piece: i32) -> (i32, i32) {
-    let board = convert_term_to_board(board);
-    let game = GameState::new(board, active_piece, AGENT);
-    let agent = Agent::new(3000, 1.5);
-    let selected_move = agent.select_move(game);
-    (selected_move.position, selected_move.next_piece)
+fn new_session() -> ResourceArc<SearchSession> {
+    ResourceArc::new(SearchSession::new())
+}
+
+// Same as choose_position_and_next_piece, but the search carries on from the
+// tree the session kept after the previous move, which keeps growing in the
+// background while the user thinks.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn choose_position_and_next_piece_in_session(
+    session: ResourceArc<SearchSession>,
+    board: Term,
+    active_piece: Term,
+    difficulty: Difficulty,
+    seed: Option<u64>,
+) -> Result<(i32, i32), Atom> {
+    let game = convert_terms_to_game(board, active_piece, Player::Agent)?;
+    let selected_move = search_and_ponder(&session, difficulty, seed, game).selected_move;
+    Ok((selected_move.position, selected_move.next_piece))
 }
 
-fn convert_term_to_board(board: Term) -> Board {
-    let positions = get_tuple(board).expect("Error getting board tuple.");
+// Like choose_position_and_next_piece, but answers with the whole SearchReport.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn search(
+    board: Term,
+    active_piece: Term,
+    difficulty: Difficulty,
+    seed: Option<u64>,
+) -> Result<ReportMap, Atom> {
+    let game = convert_terms_to_game(board, active_piece, Player::Agent)?;
+    Ok(report_map(build_agent(difficulty, seed).search(game)))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn search_in_session(
+    session: ResourceArc<SearchSession>,
+    board: Term,
+    active_piece: Term,
+    difficulty: Difficulty,
+    seed: Option<u64>,
+) -> Result<ReportMap, Atom> {
+    let game = convert_terms_to_game(board, active_piece, Player::Agent)?;
+    Ok(report_map(search_and_ponder(
+        &session, difficulty, seed, game,
+    )))
+}
+
+// The piece the AI hands the user when it has nothing to place, as when it
+// opens the game.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn choose_piece(board: Term, difficulty: Difficulty) -> Result<i32, Atom> {
+    let board = convert_term_to_board(board)?;
+    build_agent(difficulty, None)
+        .choose_piece(board)
+        .map_err(error_atom)
+}
+
+// Hints for the human player: the same search as the AI's at difficulty, but
+// playing the user's side, answered with its best num_candidates moves. A game
+// decided without searching, by a winning move or the solver, only has the one
+// move to suggest.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn suggest_move(
+    board: Term,
+    active_piece: Term,
+    num_candidates: usize,
+    difficulty: Difficulty,
+) -> Result<Vec<ChildMap>, Atom> {
+    let game = convert_terms_to_game(board, active_piece, Player::Opponent)?;
+    let report = build_agent(difficulty, None).search(game);
+    Ok(report
+        .candidates(num_candidates)
+        .into_iter()
+        .map(child_map)
+        .collect())
+}
+
+// Search in the session, then keep searching the game the AI's move leaves
+// until the next call. Seeded searches start from fresh trees and don't ponder,
+// so they can be replayed.
+fn search_and_ponder(
+    session: &SearchSession,
+    difficulty: Difficulty,
+    seed: Option<u64>,
+    game: GameState,
+) -> SearchReport {
+    let agent = build_agent(difficulty, seed);
+    if seed.is_some() {
+        session.clear();
+    }
+    let report = session.search(&agent, game);
+    if seed.is_none() {
+        session.start_pondering(agent);
+    }
+    report
+}
+
+// Searches use every thread, sessions included: a session keeps one tree for
+// each of them.
+fn build_agent(difficulty: Difficulty, seed: Option<u64>) -> Agent {
+    let builder = difficulty
+        .agent_builder()
+        .num_threads(search_threads())
+        .transposition_table(true);
+    match seed {
+        Some(seed) => builder.seed(seed),
+        None => builder,
+    }
+    .build()
+}
+
+// Everything coming in from Elixir is checked here, so a bad board is answered
+// with an error tuple instead of a panic inside the NIF.
+fn convert_terms_to_game(
+    board: Term,
+    active_piece: Term,
+    player: Player,
+) -> Result<GameState, Atom> {
+    let board = convert_term_to_board(board)?;
+    let active_piece = active_piece
+        .decode()
+        .map_err(|_| atoms::invalid_active_piece())?;
+    GameState::validated(board, active_piece, player).map_err(error_atom)
+}
+
+fn convert_term_to_board(board: Term) -> Result<Board, Atom> {
+    let positions = get_tuple(board).map_err(|_| atoms::not_a_tuple())?;
+    if positions.len() != 16 {
+        return Err(atoms::wrong_board_size());
+    }
+
     let mut board = new_board();
     for (i, pos) in positions.iter().enumerate() {
         // board elements are either nil or an int.
-        // nil comes in as an atom here.
-        if pos.is_atom() {
-            board[i] = None;
-        } else {
-            board[i] = pos.decode().expect("Position isn't an i32");
+        if atoms::nil() != *pos {
+            board[i] = Some(pos.decode().map_err(|_| atoms::invalid_piece())?);
         }
     }
-    board
+    Ok(board)
+}
+
+fn search_threads() -> usize {
+    thread::available_parallelism()
+        .map(|cores| cores.get().min(MAX_SEARCH_THREADS))
+        .unwrap_or(1)
+}
+
+fn report_map(report: SearchReport) -> ReportMap {
+    ReportMap {
+        position: report.selected_move.position,
+        next_piece: report.selected_move.next_piece,
+        outcome: report.outcome.map(outcome_atom),
+        rounds: report.rounds,
+        elapsed_ms: report.elapsed.as_millis() as u64,
+        tree_size: report.tree_size,
+        seed: report.seed,
+        children: report.children.into_iter().map(child_map).collect(),
+        // The AI makes the first move of the line, then the players take turns.
+        principal_variation: report
+            .principal_variation
+            .into_iter()
+            .zip([Player::Agent, Player::Opponent].into_iter().cycle())
+            .map(|(node_move, player)| move_map(node_move, player))
+            .collect(),
+    }
+}
+
+fn child_map(child: ChildReport) -> ChildMap {
+    ChildMap {
+        position: child.node_move.position,
+        piece: child.node_move.piece,
+        next_piece: child.node_move.next_piece,
+        visits: child.visits,
+        win: child.win,
+        draw: child.draw,
+        loss: child.loss,
+        outcome: child.outcome.map(outcome_atom),
+    }
+}
+
+fn move_map(node_move: Move, player: Player) -> MoveMap {
+    MoveMap {
+        player,
+        position: node_move.position,
+        piece: node_move.piece,
+        next_piece: node_move.next_piece,
+    }
+}
+
+fn outcome_atom(outcome: Outcome) -> Atom {
+    match outcome {
+        Outcome::Win => atoms::win(),
+        Outcome::Draw => atoms::draw(),
+        Outcome::Loss => atoms::loss(),
+    }
+}
+
+fn error_atom(error: GameError) -> Atom {
+    match error {
+        GameError::InvalidPiece => atoms::invalid_piece(),
+        GameError::DuplicatePiece => atoms::duplicate_piece(),
+        GameError::InvalidActivePiece => atoms::invalid_active_piece(),
+        GameError::ActivePieceOnBoard => atoms::active_piece_on_board(),
+        GameError::GameOver => atoms::game_over(),
+    }
 }
 
 // Since rustler 0.34 the NIF list is discovered automatically from #[rustler::nif]
@@ -1,180 +1,772 @@
-use super::{Node, NodeBuilder};
-use crate::game::{GameState, Move, Player};
-use rand::Rng;
-use std::rc::Rc;
+use super::move_selection::visits_and_value_agree;
+use super::rave::propagate_amaf;
+use super::report::expected_line;
+use super::{
+    ChildStats, MCTNode, MoveSelection, NodeId, Proven, RaveSchedule, RolloutPolicy, SearchReport,
+    SelectionPolicy, Tree, Ucb1, UniformRollout, ROOT,
+};
+use crate::game::{remaining_pieces, Board, GameError, GameState, Move, Piece, Player, Step};
+use crate::solver::{Outcome, Solver};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_NUM_ROUNDS: u32 = 3000;
+const DEFAULT_SOLVER_THRESHOLD: u32 = 9;
+const DEFAULT_DRAW_REWARD: f64 = 0.5;
+// How far past its time budget a RobustMax search may go, as a share of it.
+const ROBUST_MAX_GRACE: f64 = 0.5;
+// How much of the time budget the exact solver may spend before the search
+// falls back to sampling with the rest.
+const SOLVER_SHARE: f64 = 0.5;
+// The least time a share of a split budget gets, enough for some rounds or a
+// start at solving. Splitting many ways can overrun the budget by this much a share.
+const MIN_TIME_SHARE: Duration = Duration::from_millis(5);
 
 /* Monte Carlo Tree Search
 
  - create tree for given game state
  - start a round:
    - pick a leaf node
-   - randomly place the active piece and choose the next piece
+   - take a random step from it: place the active piece, or choose the next piece
    - add new child node with this game state
    - execute rollout (simulate game from this node to see who wins)
    - record the win in this node
    - walkup all node ancestors and update their win counts
- - Set this to a certain number of rounds
-   - Once limit is reached, select the child node of the root that has the highest win rate
+ - Keep going until the round cap or the time budget runs out, whichever comes first
+   - Once limit is reached, pick a child node of the root by the move selection strategy
+ - Skip all of that when the board is nearly full, and solve the game exactly instead
 
 */
 pub struct Agent {
-    num_rounds: i32,
-    temperature: f64, // For UCT - higher is volatile, lower is focused
+    num_rounds: Option<u32>,
+    time_budget: Option<Duration>,
+    temperature: f64, // Exploration for the selection policy - higher is volatile, lower is focused
+    num_threads: usize,
+    solver_threshold: u32,
+    transposition_table: bool,
+    seed: Option<u64>,
+    blunder_probability: f64,
+    draw_reward: f64, // Between a loss at 0 and a win at 1
+    rollout_policy: Arc<dyn RolloutPolicy>,
+    rave: Option<RaveSchedule>,
+    selection_policy: Arc<dyn SelectionPolicy>,
+    move_selection: MoveSelection,
 }
 
-impl Agent {
-    pub fn new(num_rounds: i32, temperature: f64) -> Self {
+pub struct AgentBuilder {
+    pub num_rounds: Option<u32>,
+    pub time_budget: Option<Duration>,
+    pub temperature: f64,
+    pub num_threads: usize,
+    pub solver_threshold: u32,
+    pub transposition_table: bool,
+    pub seed: Option<u64>,
+    pub blunder_probability: f64,
+    pub draw_reward: f64,
+    pub rollout_policy: Arc<dyn RolloutPolicy>,
+    pub rave: Option<RaveSchedule>,
+    pub selection_policy: Arc<dyn SelectionPolicy>,
+    pub move_selection: MoveSelection,
+}
+
+impl AgentBuilder {
+    pub fn new(temperature: f64) -> Self {
         Self {
-            num_rounds,
+            num_rounds: None,
+            time_budget: None,
             temperature,
+            num_threads: 1,
+            solver_threshold: DEFAULT_SOLVER_THRESHOLD,
+            transposition_table: false,
+            seed: None,
+            blunder_probability: 0.0,
+            draw_reward: DEFAULT_DRAW_REWARD,
+            rollout_policy: Arc::new(UniformRollout),
+            rave: None,
+            selection_policy: Arc::new(Ucb1),
+            move_selection: MoveSelection::MaxVisits,
         }
     }
 
+    pub fn num_rounds(mut self, num_rounds: u32) -> Self {
+        self.num_rounds = Some(num_rounds);
+        self
+    }
+
+    pub fn time_budget(mut self, time_budget: Duration) -> Self {
+        self.time_budget = Some(time_budget);
+        self
+    }
+
+    // Search this many independent trees at once. The round cap and time budget
+    // apply to each thread, so more threads means more rounds in the same time.
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = num_threads.max(1);
+        self
+    }
+
+    // Solve games exactly, rather than searching them, once there are this many
+    // empty squares or fewer. Zero turns the solver off.
+    pub fn solver_threshold(mut self, solver_threshold: u32) -> Self {
+        self.solver_threshold = solver_threshold;
+        self
+    }
+
+    // Share one node between every move order that reaches the same game.
+    pub fn transposition_table(mut self, transposition_table: bool) -> Self {
+        self.transposition_table = transposition_table;
+        self
+    }
+
+    // Make every random choice from this seed, so a search can be replayed.
+    // Only searches limited by rounds rather than time come out the same.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    // Play a random move instead of the one searched for this often, from 0 to 1.
+    pub fn blunder_probability(mut self, blunder_probability: f64) -> Self {
+        self.blunder_probability = blunder_probability;
+        self
+    }
+
+    // How rollouts pick their moves, shared with whoever else holds the policy.
+    pub fn shared_rollout_policy(mut self, rollout_policy: Arc<dyn RolloutPolicy>) -> Self {
+        self.rollout_policy = rollout_policy;
+        self
+    }
+
+    pub fn build(self) -> Agent {
+        // Without any limit the search would never end.
+        let num_rounds = match (self.num_rounds, self.time_budget) {
+            (None, None) => Some(DEFAULT_NUM_ROUNDS),
+            (num_rounds, _) => num_rounds,
+        };
+
+        Agent {
+            num_rounds,
+            time_budget: self.time_budget,
+            temperature: self.temperature,
+            num_threads: self.num_threads,
+            solver_threshold: self.solver_threshold,
+            transposition_table: self.transposition_table,
+            seed: self.seed,
+            blunder_probability: self.blunder_probability,
+            draw_reward: self.draw_reward,
+            rollout_policy: self.rollout_policy,
+            rave: self.rave,
+            selection_policy: self.selection_policy,
+            move_selection: self.move_selection,
+        }
+    }
+}
+
+// Variations on the search that no preset uses yet, there to tune them with.
+#[allow(dead_code)]
+impl AgentBuilder {
+    // What a draw is worth, between a loss at 0 and a win at 1.
+    pub fn draw_reward(mut self, draw_reward: f64) -> Self {
+        self.draw_reward = draw_reward;
+        self
+    }
+
+    // How rollouts pick their moves. Smarter policies cost more per rollout
+    // but tell a lot more about the position.
+    pub fn rollout_policy(self, rollout_policy: impl RolloutPolicy + 'static) -> Self {
+        self.shared_rollout_policy(Arc::new(rollout_policy))
+    }
+
+    // Blend every child's all-moves-as-first value into its score, weighted by
+    // the schedule, so children get a useful value before many visits of their own.
+    pub fn rave(mut self, schedule: RaveSchedule) -> Self {
+        self.rave = Some(schedule);
+        self
+    }
+
+    // How the search picks which child to go down next, using the temperature
+    // as its exploration constant.
+    pub fn selection_policy(mut self, selection_policy: impl SelectionPolicy + 'static) -> Self {
+        self.selection_policy = Arc::new(selection_policy);
+        self
+    }
+
+    // How the move gets picked from the searched children once the search is done.
+    pub fn move_selection(mut self, move_selection: MoveSelection) -> Self {
+        self.move_selection = move_selection;
+        self
+    }
+}
+
+impl Agent {
+    #[allow(dead_code)] // The NIFs build their agents from a difficulty.
+    pub fn new(num_rounds: u32, temperature: f64) -> Self {
+        AgentBuilder::new(temperature)
+            .num_rounds(num_rounds)
+            .build()
+    }
+
     pub fn select_move(&self, game: GameState) -> Move {
-        let root = NodeBuilder::new(game).build();
+        self.search(game).selected_move
+    }
 
-        // If agent is given a winning move, take it!
-        match root.borrow().game_state.winning_move() {
-            Some(winning_move) => return winning_move,
-            None => (),
+    pub fn search(&self, game: GameState) -> SearchReport {
+        let report = if self.num_threads > 1 {
+            self.search_root_parallel(game.clone())
+        } else {
+            self.grow_tree(&mut self.new_tree(game.clone()))
+        };
+        self.maybe_blunder(&game, report)
+    }
+
+    pub fn new_tree(&self, game: GameState) -> Tree {
+        if self.transposition_table {
+            Tree::with_transpositions(game)
+        } else {
+            Tree::new(game)
         }
+    }
 
-        for _ in 0..self.num_rounds {
-            self.execute_round(root.clone());
+    // Keep growing a tree that may already hold statistics from earlier searches.
+    // This always runs on the calling thread, whatever num_threads is set to.
+    pub fn search_tree(&self, tree: &mut Tree) -> SearchReport {
+        let game = tree[ROOT].game_state.clone();
+        let report = self.grow_tree(tree);
+        self.maybe_blunder(&game, report)
+    }
+
+    // Grow the tree in the background while the other player thinks, within
+    // the usual budget, until stop is set. Returns the rounds searched.
+    pub fn ponder(&self, tree: &mut Tree, stop: &AtomicBool) -> u32 {
+        let started_at = Instant::now();
+        let mut rng = StdRng::seed_from_u64(self.pick_seed());
+        let mut rounds = 0;
+        while tree[ROOT].proven.is_none()
+            && !stop.load(Ordering::Relaxed)
+            && !self.out_of_budget(rounds, started_at)
+        {
+            self.execute_round(tree, &mut rng);
+            rounds += 1;
+        }
+        rounds
+    }
+
+    // Like search_tree, but grows each tree on a thread of its own, as a root
+    // parallel search does, and picks the move from all of them. The trees
+    // must all start from the same game.
+    pub fn search_trees(&self, trees: &mut [Tree]) -> SearchReport {
+        if let [tree] = trees {
+            return self.search_tree(tree);
+        }
+        let game = trees[0][ROOT].game_state.clone();
+        let report = self.grow_trees(trees);
+        self.maybe_blunder(&game, report)
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.num_threads
+    }
+
+    fn grow_tree(&self, tree: &mut Tree) -> SearchReport {
+        let started_at = Instant::now();
+        let seed = self.pick_seed();
+        if let Some(mut report) = self.decide_without_search(&tree[ROOT].game_state, started_at) {
+            report.tree_size = tree.size();
+            return report.finished(0, started_at, seed);
         }
 
+        let mut rng = StdRng::seed_from_u64(seed);
+        let rounds = self.execute_rounds(tree, started_at, &mut rng);
+
         // Having performed as many MCTS rounds as we have time for, we now pick a move.
-        self.pick_best_move(root)
+        let selected_move = self.pick_best_move(tree);
+        let outcome = proven_outcome(tree);
+        SearchReport::sampled(tree, selected_move, outcome).finished(rounds, started_at, seed)
+    }
+
+    // Pick the piece to hand the opponent when there is nothing to place, as at
+    // the start of the game: the one the opponent can do least with. Pieces that
+    // are the same up to symmetry are only searched once, so the opening, where
+    // every piece is alike, needs no search at all.
+    pub fn choose_piece(&self, board: Board) -> Result<Piece, GameError> {
+        let mut seen = HashSet::new();
+        let mut candidates: Vec<GameState> = remaining_pieces(&board)?
+            .into_iter()
+            .map(|piece| GameState::new(board, piece, Player::Opponent))
+            .filter(|game| seen.insert(game.canonical().0.position_key()))
+            .collect();
+
+        let mut rng = StdRng::seed_from_u64(self.pick_seed());
+        if self.blunder_probability > 0.0 && rng.gen_bool(self.blunder_probability.min(1.0)) {
+            return Ok(handed_over(&candidates[rng.gen_range(0..candidates.len())]));
+        }
+
+        // Never hand over a piece that wins on the spot, unless they all do.
+        if candidates.iter().any(|game| game.winning_move().is_none()) {
+            candidates.retain(|game| game.winning_move().is_none());
+        }
+        if let [only_candidate] = candidates.as_slice() {
+            return Ok(handed_over(only_candidate));
+        }
+
+        let agent = self.with_budget_split(candidates.len() as u32);
+        let values: Vec<f64> = candidates
+            .iter()
+            .map(|game| agent.value_for_player_to_move(game))
+            .collect();
+        let best = (0..candidates.len())
+            .min_by(|&a, &b| values[a].total_cmp(&values[b]))
+            .expect("No piece to choose from");
+        Ok(handed_over(&candidates[best]))
+    }
+
+    // Share the round cap and time budget between this many searches. Each
+    // share is a search of its own, solver and all, held to its own part.
+    fn with_budget_split(&self, shares: u32) -> Agent {
+        Agent {
+            num_rounds: self
+                .num_rounds
+                .map(|num_rounds| (num_rounds / shares).max(1)),
+            time_budget: self
+                .time_budget
+                .map(|time_budget| (time_budget / shares).max(MIN_TIME_SHARE)),
+            blunder_probability: 0.0,
+            rollout_policy: self.rollout_policy.clone(),
+            selection_policy: self.selection_policy.clone(),
+            ..*self
+        }
+    }
+
+    // Expected score for whoever moves next: 1 for a win, draw_reward for a draw.
+    fn value_for_player_to_move(&self, game: &GameState) -> f64 {
+        let report = self.search(game.clone());
+        match report.outcome {
+            Some(Outcome::Win) => 1.0,
+            Some(Outcome::Draw) => self.draw_reward,
+            Some(Outcome::Loss) => 0.0,
+            None => report.selected_child().map_or(self.draw_reward, |child| {
+                child.win + self.draw_reward * child.draw
+            }),
+        }
     }
 
-    fn execute_round(&self, root: Node) {
-        // Find a node to add a child to
-        let mut node = root.clone();
-        while !node.borrow().can_add_child() && !node.borrow().is_terminal() {
-            node = self.select_child(node.clone());
+    // Now and then throw the search away and play some other move at random,
+    // for opponents that aren't meant to be hard to beat.
+    fn maybe_blunder(&self, game: &GameState, mut report: SearchReport) -> SearchReport {
+        if self.blunder_probability <= 0.0 {
+            return report;
         }
 
-        // Add a new move into the tree
-        if node.borrow().can_add_child() {
-            node = self.add_child_for_random_move(node.clone());
+        // A stream of its own, so blundering doesn't change what the search did.
+        let mut rng = StdRng::seed_from_u64(report.seed.rotate_left(32));
+        if !rng.gen_bool(self.blunder_probability.min(1.0)) {
+            return report;
+        }
+        let other_moves: Vec<Move> = game
+            .legal_moves()
+            .into_iter()
+            .filter(|legal_move| *legal_move != report.selected_move)
+            .collect();
+        if other_moves.is_empty() {
+            return report;
         }
 
-        // Simulate a random game from this node
-        let winner = self.simulate_random_game(&node.borrow().game_state);
-        node.borrow_mut().propagate_wins(winner);
+        let blunder = other_moves[rng.gen_range(0..other_moves.len())].clone();
+        report.principal_variation = vec![blunder.clone()];
+        report.selected_move = blunder;
+        report.outcome = None;
+        report
+    }
+
+    // Unseeded agents still pick a seed, so the report can say how to repeat the search.
+    fn pick_seed(&self) -> u64 {
+        self.seed.unwrap_or_else(rand::random)
+    }
+
+    fn decide_without_search(&self, game: &GameState, started_at: Instant) -> Option<SearchReport> {
+        // If agent is given a winning move, take it!
+        let (selected_move, outcome) = if let Some(winning_move) = game.winning_move() {
+            (winning_move, Outcome::Win)
+        } else if game.num_empty_positions() <= self.solver_threshold {
+            let mut solver = match self.time_budget {
+                Some(time_budget) => {
+                    Solver::with_deadline(started_at + time_budget.mul_f64(SOLVER_SHARE))
+                }
+                None => Solver::new(),
+            };
+            let solution = solver.try_solve(game)?;
+            (solution.best_move, solution.outcome)
+        } else {
+            return None;
+        };
+
+        Some(SearchReport::decided(selected_move, outcome))
     }
 
-    // Select child node with highest UCT score.
-    pub fn select_child(&self, node: Node) -> Node {
+    fn execute_rounds(&self, tree: &mut Tree, started_at: Instant, rng: &mut StdRng) -> u32 {
+        let mut rounds = 0;
+        while tree[ROOT].proven.is_none() && !self.out_of_budget(rounds, started_at) {
+            self.execute_round(tree, rng);
+            rounds += 1;
+        }
+
+        // Each thread settles its own tree.
+        if let MoveSelection::RobustMax(extra_rounds) = self.move_selection {
+            let mut extra = 0;
+            while tree[ROOT].proven.is_none()
+                && extra < extra_rounds
+                && !self.past_grace_period(started_at)
+                && !visits_and_value_agree(tree, self.draw_reward)
+            {
+                self.execute_round(tree, rng);
+                extra += 1;
+            }
+            rounds += extra;
+        }
+        rounds
+    }
+
+    // Root parallelization: every thread grows its own tree from the same game,
+    // then the statistics of the root's children are summed move by move into a
+    // fresh root, which is what the best move gets picked from.
+    fn search_root_parallel(&self, game: GameState) -> SearchReport {
+        let mut trees: Vec<Tree> = (0..self.num_threads)
+            .map(|_| self.new_tree(game.clone()))
+            .collect();
+        self.grow_trees(&mut trees)
+    }
+
+    fn grow_trees(&self, trees: &mut [Tree]) -> SearchReport {
+        let started_at = Instant::now();
+        let seed = self.pick_seed();
+        let game = trees[0][ROOT].game_state.clone();
+        if let Some(mut report) = self.decide_without_search(&game, started_at) {
+            report.tree_size = trees.iter().map(Tree::size).sum();
+            return report.finished(0, started_at, seed);
+        }
+
+        let rounds: u32 = thread::scope(|scope| {
+            let workers: Vec<_> = trees
+                .iter_mut()
+                .zip(0u64..)
+                .map(|(tree, worker)| {
+                    scope.spawn(move || {
+                        // Each thread gets its own stream of random numbers.
+                        let mut rng = StdRng::seed_from_u64(seed.wrapping_add(worker));
+                        self.execute_rounds(tree, started_at, &mut rng)
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("Search thread panicked"))
+                .sum()
+        });
+
+        let mut merged = Tree::new(game);
+        for tree in trees.iter() {
+            merge_root_children(&mut merged, tree);
+        }
+
+        let selected_move = self.pick_best_move(&merged);
+        let outcome = proven_outcome(&mut merged);
+        let mut report = SearchReport::sampled(&merged, selected_move, outcome);
+        report.tree_size = trees.iter().map(Tree::size).sum();
+        // The merged tree stops at the root's children, so the rest of the line
+        // comes from the thread that looked hardest at the selected move.
+        let visits_of_selected_move = |tree: &Tree| {
+            tree.child_for_move(ROOT, &report.selected_move)
+                .map_or(0, |child| tree[child].num_rollouts)
+        };
+        if let Some(tree) = trees
+            .iter()
+            .max_by_key(|tree| visits_of_selected_move(tree))
+        {
+            report.principal_variation = expected_line(tree, &report.selected_move);
+        }
+        report.finished(rounds, started_at, seed)
+    }
+
+    fn out_of_budget(&self, rounds: u32, started_at: Instant) -> bool {
+        // Always search one round, so there is a move to pick even when the
+        // budget is gone before the search gets going.
+        if rounds == 0 {
+            return false;
+        }
+        if let Some(num_rounds) = self.num_rounds {
+            if rounds >= num_rounds {
+                return true;
+            }
+        }
+        match self.time_budget {
+            Some(time_budget) => started_at.elapsed() >= time_budget,
+            None => false,
+        }
+    }
+
+    fn past_grace_period(&self, started_at: Instant) -> bool {
+        self.time_budget.is_some_and(|time_budget| {
+            started_at.elapsed() >= time_budget.mul_f64(1.0 + ROBUST_MAX_GRACE)
+        })
+    }
+
+    fn execute_round(&self, tree: &mut Tree, rng: &mut StdRng) {
+        // Find a node to add a child to, remembering the way down since shared
+        // nodes have more than one parent. Stop early at a node whose result is
+        // already proven.
+        let mut path = vec![ROOT];
+        let mut node = ROOT;
+        while tree.prove(node).is_none() && !tree[node].can_add_child() {
+            node = self.select_child(tree, node, rng);
+            path.push(node);
+        }
+
+        // Add a new step into the tree
+        if tree[node].proven.is_none() {
+            node = self.add_child_for_random_step(tree, node, rng);
+            path.push(node);
+        }
+
+        // Simulate a game from this node, unless there is nothing left to find out
+        let mut rollout_steps = Vec::new();
+        let winner = match tree[node].proven {
+            Some(proven) => proven.winner(),
+            None => self.simulate_game(&tree[node].game_state, rng, &mut rollout_steps),
+        };
+        tree.propagate_wins(&path, winner);
+        tree.prove_path(&path);
+
+        if self.rave.is_some() {
+            let mut steps: Vec<(Player, Step)> = path
+                .windows(2)
+                .map(|pair| {
+                    let player = tree[pair[0]].game_state.current_player;
+                    (player, tree.step_between(pair[0], pair[1]))
+                })
+                .collect();
+            steps.extend(rollout_steps);
+            propagate_amaf(tree, &path, &steps, winner);
+        }
+    }
+
+    // Select the child the selection policy scores highest, never one proven to lose.
+    pub fn select_child(&self, tree: &Tree, node: NodeId, rng: &mut StdRng) -> NodeId {
+        let parent = &tree[node];
         let mut total_rollouts = 0.0;
-        for child in &node.borrow().children {
-            total_rollouts += child.borrow().num_rollouts as f64;
+        for &child in &parent.children {
+            total_rollouts += tree[child].num_rollouts as f64;
         }
 
-        let mut best_score = -1.0;
+        let mut best_score = f64::NEG_INFINITY;
         let mut best_child = None;
-        for child in &node.borrow().children {
-            let uct_score = self.calculate_uct_score(
+        for &child in &parent.children {
+            if is_proven_loss(tree, node, child) {
+                continue;
+            }
+
+            let stats = self.child_stats(
                 total_rollouts,
-                child.borrow().num_rollouts as f64,
-                child
-                    .borrow()
-                    .winning_fraction(node.borrow().game_state.current_player),
+                &tree[child],
+                parent.game_state.current_player,
             );
+            let score = self.selection_policy.score(&stats, self.temperature, rng);
 
-            if uct_score > best_score {
-                best_score = uct_score;
-                best_child = Some(child.clone());
+            if score > best_score {
+                best_score = score;
+                best_child = Some(child);
             }
         }
         best_child.expect("Child was not found")
     }
 
-    // Calculate upper confidence bound for trees (UCT).
-    // This gives you a balance between exploration (breadth) and exploitation (depth).
-    fn calculate_uct_score(&self, parent_rollouts: f64, child_rollouts: f64, win_pct: f64) -> f64 {
-        let exploration = (parent_rollouts.log10() / child_rollouts).sqrt();
-        win_pct + self.temperature * exploration
+    // What the selection policy gets to see of a child, for the player choosing.
+    fn child_stats(&self, parent_rollouts: f64, child: &MCTNode, player: Player) -> ChildStats {
+        let reward_variance = if child.num_rollouts > 0 {
+            child.reward_variance(player, self.draw_reward)
+        } else {
+            0.0
+        };
+        ChildStats {
+            visits: child.num_rollouts as f64,
+            parent_visits: parent_rollouts,
+            reward: self.estimated_reward(child, player),
+            reward_variance,
+            prior: child.prior,
+        }
+    }
+
+    // The child's mean reward, blended with its all-moves-as-first reward when
+    // RAVE is on. Children without rollouts count as a draw.
+    fn estimated_reward(&self, child: &MCTNode, player: Player) -> f64 {
+        let reward = if child.num_rollouts > 0 {
+            child.mean_reward(player, self.draw_reward)
+        } else {
+            self.draw_reward
+        };
+        match self.rave {
+            Some(schedule) if child.amaf_rollouts > 0 => {
+                let weight = schedule.weight(child.num_rollouts as f64, child.amaf_rollouts as f64);
+                let amaf_reward = child.amaf_reward(player, self.draw_reward);
+                (1.0 - weight) * reward + weight * amaf_reward
+            }
+            _ => reward,
+        }
     }
 
-    fn add_child_for_random_move(&self, node: Node) -> Node {
-        let next_move = node.borrow_mut().random_legal_move();
-        let new_game_state = node.borrow().game_state.apply_move(&next_move);
-        let child = NodeBuilder::new(new_game_state)
-            .node_move(next_move)
-            .parent(Rc::downgrade(&node))
-            .build();
-        node.borrow_mut().children.push(child);
-        node.borrow().children.last().unwrap().clone()
+    fn add_child_for_random_step(&self, tree: &mut Tree, node: NodeId, rng: &mut StdRng) -> NodeId {
+        let step = tree[node].random_unvisited_step(rng);
+        self.add_child_with_prior(tree, node, step)
+    }
+
+    // Give a new child the selection policy's prior. A node shared with
+    // another parent keeps the prior it got when that parent added it.
+    fn add_child_with_prior(&self, tree: &mut Tree, node: NodeId, step: Step) -> NodeId {
+        let size = tree.size();
+        let child = tree.add_child(node, step);
+        if tree.size() > size {
+            tree[child].prior = self.selection_policy.prior(&tree[node].game_state, step);
+        }
+        child
     }
 
-    fn simulate_random_game(&self, game: &GameState) -> Option<Player> {
+    // Play the rollout policy's steps until the game ends, each chosen from the
+    // position the game has reached, and report who won. The game may start
+    // halfway through a turn, with a piece placed and none handed over yet.
+    // Every step goes into steps, with the player who took it.
+    fn simulate_game(
+        &self,
+        game: &GameState,
+        rng: &mut StdRng,
+        steps: &mut Vec<(Player, Step)>,
+    ) -> Option<Player> {
         let mut current_game = game.clone();
         while !current_game.is_over() {
-            let next_move = self.select_random_move(game);
-            current_game = current_game.apply_move(&next_move);
+            let step = match current_game.active_piece() {
+                Some(_) => Step::Place(self.rollout_policy.select_position(&current_game, rng)),
+                None => Step::Give(self.rollout_policy.select_piece(&current_game, rng)),
+            };
+            steps.push((current_game.current_player, step));
+            current_game = current_game.apply_step(step);
         }
         current_game.winner()
     }
 
-    fn select_random_move(&self, game: &GameState) -> Move {
-        let mut rng = rand::thread_rng();
-        let legal_moves = game.legal_moves();
-        let index: usize = rng.gen_range(0..legal_moves.len());
-        legal_moves[index].clone()
+    // The best square for the active piece, then the best piece to hand over
+    // once it's there.
+    fn pick_best_move(&self, tree: &Tree) -> Move {
+        let piece = tree[ROOT]
+            .game_state
+            .active_piece()
+            .expect("The root has a piece to place");
+        let placed = self.pick_best_child(tree, ROOT);
+        let position = match tree.step_between(ROOT, placed) {
+            Step::Place(position) => position,
+            Step::Give(_) => unreachable!("The root has a piece to place"),
+        };
+
+        let next_piece = if tree[placed].is_terminal() {
+            0
+        } else if tree[placed].children.is_empty() {
+            unsearched_piece(&tree[placed].game_state)
+        } else {
+            match tree.step_between(placed, self.pick_best_child(tree, placed)) {
+                Step::Give(next_piece) => next_piece,
+                Step::Place(_) => unreachable!("The piece was just placed"),
+            }
+        };
+
+        Move {
+            position,
+            piece,
+            next_piece,
+        }
     }
 
-    fn pick_best_move(&self, node: Node) -> Move {
-        let mut best_move = None;
-        let mut best_percent = -1.0;
+    fn pick_best_child(&self, tree: &Tree, node: NodeId) -> NodeId {
+        self.move_selection.pick(tree, node, self.draw_reward)
+    }
+}
 
-        for child in &node.borrow().children {
-            if self.is_losing_move(child.clone(), node.clone()) {
-                continue;
-            }
+// A piece to hand over when the search never got that far: one that can't
+// win on the spot, if there is one.
+fn unsearched_piece(game: &GameState) -> Piece {
+    game.pieces_to_give()
+        .find(|&piece| game.give(piece).winning_move().is_none())
+        .or_else(|| game.pieces_to_give().next())
+        .expect("There is no piece left to hand over")
+}
 
-            let child_percent = child
-                .borrow()
-                .winning_fraction(node.borrow().game_state.current_player);
+// The piece choose_piece would hand over to get to one of its candidate games.
+fn handed_over(game: &GameState) -> Piece {
+    game.active_piece()
+        .expect("Every candidate has a piece to place")
+}
 
-            if child_percent > best_percent {
-                best_percent = child_percent;
-                best_move = child.borrow().node_move.clone();
-            }
-        }
+fn proven_outcome(tree: &mut Tree) -> Option<Outcome> {
+    let player = tree[ROOT].game_state.current_player;
+    tree.prove(ROOT).map(|proven| proven.outcome_for(player))
+}
 
-        if best_move.is_none() {
-            best_move = node
-                .borrow()
-                .children
-                .first()
-                .unwrap()
-                .borrow()
-                .node_move
-                .clone();
-        }
-        best_move.expect("Best move not found")
+// Whether the player to move at node loses for sure by moving to child.
+pub(super) fn is_proven_loss(tree: &Tree, node: NodeId, child: NodeId) -> bool {
+    match tree[child].proven {
+        Some(Proven::Win(winner)) => winner != tree[node].game_state.current_player,
+        _ => false,
     }
+}
 
-    fn is_losing_move(&self, child: Node, parent: Node) -> bool {
-        let child_ref = child.borrow();
-        let child_move = child_ref.node_move.as_ref().unwrap();
-        let new_game = parent.borrow().game_state.apply_move(child_move);
-        for legal_move in new_game.legal_moves() {
-            if new_game.apply_move(&legal_move).is_over() {
-                return true;
-            }
+// Sum the statistics of every square tried from the root, and of every piece
+// handed over after it, into the merged tree step by step.
+fn merge_root_children(merged: &mut Tree, tree: &Tree) {
+    for &placed in &tree[ROOT].children {
+        let merged_placed = merge_child(merged, ROOT, tree, ROOT, placed);
+        merged[ROOT].num_rollouts += tree[placed].num_rollouts;
+        for &given in &tree[placed].children {
+            merge_child(merged, merged_placed, tree, placed, given);
         }
-        false
     }
 }
 
+fn merge_child(
+    merged: &mut Tree,
+    merged_parent: NodeId,
+    tree: &Tree,
+    parent: NodeId,
+    child: NodeId,
+) -> NodeId {
+    let step = tree.step_between(parent, child);
+    let merged_child = merged
+        .child_for_step(merged_parent, step)
+        .unwrap_or_else(|| merged.add_child(merged_parent, step));
+
+    let child = &tree[child];
+    for player in Player::ALL {
+        merged[merged_child].win_counts[player.index()] += child.wins(player);
+    }
+    merged[merged_child].num_rollouts += child.num_rollouts;
+    for player in Player::ALL {
+        merged[merged_child].amaf_win_counts[player.index()] += child.amaf_wins(player);
+    }
+    merged[merged_child].amaf_rollouts += child.amaf_rollouts;
+    merged[merged_child].proven = merged[merged_child].proven.or(child.proven);
+    merged_child
+}
+
 #[cfg(test)]
 mod tests {
-    use super::super::{NodeBuilder, AGENT};
     use super::*;
-    use crate::game::{new_board, GameState};
-    use std::collections::HashMap;
+    use crate::game::{new_board, Board, GameState};
+    use crate::mcts::{Puct, ThompsonSampling, Ucb1Tuned};
+
+    fn place_piece(tree: &mut Tree, position: i32) -> NodeId {
+        tree.add_child(ROOT, Step::Place(position))
+    }
 
     #[test]
     fn select_move_bug() {
@@ -197,19 +789,73 @@ mod tests {
             Some(0),
             None,
         ];
-        let game = GameState::new(board, 3, AGENT);
-        let agent = Agent::new(3000, 1.5);
+        let game = GameState::new(board, 3, Player::Agent);
+        let agent = AgentBuilder::new(1.5).num_rounds(3000).seed(7).build();
         let selected_move = agent.select_move(game);
         assert!(selected_move.position >= 0);
     }
 
+    fn root_visits(tree: &Tree) -> Vec<(Option<Step>, i32)> {
+        tree[ROOT]
+            .children
+            .iter()
+            .map(|&child| (tree[child].step, tree[child].num_rollouts))
+            .collect()
+    }
+
+    #[test]
+    fn search_tree_repeats_itself_with_the_same_seed() {
+        let agent = AgentBuilder::new(1.5).num_rounds(500).seed(42).build();
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let mut first_tree = Tree::new(game.clone());
+        let mut second_tree = Tree::new(game);
+        let first = agent.search_tree(&mut first_tree);
+        let second = agent.search_tree(&mut second_tree);
+
+        assert_eq!(first.seed, 42);
+        assert_eq!(first.selected_move, second.selected_move);
+        assert_eq!(root_visits(&first_tree), root_visits(&second_tree));
+    }
+
+    #[test]
+    fn search_repeats_itself_across_threads_with_the_same_seed() {
+        let agent = AgentBuilder::new(1.5)
+            .num_rounds(200)
+            .num_threads(3)
+            .seed(42)
+            .build();
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let first = agent.search(game.clone());
+        let second = agent.search(game);
+        assert_eq!(first.selected_move, second.selected_move);
+    }
+
+    #[test]
+    fn search_reports_the_seed_it_picked() {
+        let agent = Agent::new(100, 1.5);
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let mut first_tree = Tree::new(game.clone());
+        let report = agent.search_tree(&mut first_tree);
+
+        let replay = AgentBuilder::new(1.5)
+            .num_rounds(100)
+            .seed(report.seed)
+            .build();
+        let mut second_tree = Tree::new(game);
+        assert_eq!(
+            replay.search_tree(&mut second_tree).selected_move,
+            report.selected_move
+        );
+        assert_eq!(root_visits(&first_tree), root_visits(&second_tree));
+    }
+
     #[test]
     fn select_move_returns_first_winning_move() {
         let mut board = new_board();
         board[0] = Some(0);
         board[1] = Some(2);
         board[2] = Some(4);
-        let game = GameState::new(board, 8, AGENT);
+        let game = GameState::new(board, 8, Player::Agent);
         let agent = Agent::new(30, 1.0);
         let selected_move = agent.select_move(game);
         assert_eq!(selected_move.position, 3);
@@ -235,65 +881,652 @@ mod tests {
             None,
             Some(5),
         ];
-        let game = GameState::new(board, 0, AGENT);
+        let game = GameState::new(board, 0, Player::Agent);
         let agent = Agent::new(30, 1.0);
         let selected_move = agent.select_move(game);
         assert!(selected_move.position >= 0);
     }
 
     #[test]
-    fn add_child_for_random_move_adds_new_node_to_tree() {
-        let game = GameState::new(new_board(), 0, AGENT);
-        let node = NodeBuilder::new(game).build();
+    fn search_tree_with_transpositions_counts_every_round_at_the_root() {
+        let agent = AgentBuilder::new(1.5)
+            .num_rounds(400)
+            .transposition_table(true)
+            .build();
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let mut tree = agent.new_tree(game.clone());
+        let report = agent.search_tree(&mut tree);
+
+        assert_eq!(tree[ROOT].num_rollouts, 400);
+        assert!(tree.size() <= 401);
+        assert!(game.legal_moves().contains(&report.selected_move));
+    }
+
+    #[test]
+    fn select_child_never_picks_a_proven_loss() {
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let mut tree = Tree::new(game);
+        let losing = place_piece(&mut tree, 0);
+        let other = place_piece(&mut tree, 1);
+        tree.propagate_wins(&[ROOT, losing], Some(Player::Agent));
+        tree.propagate_wins(&[ROOT, other], Some(Player::Opponent));
+        tree[losing].proven = Some(Proven::Win(Player::Opponent));
+
         let agent = Agent::new(5, 1.0);
-        agent.add_child_for_random_move(node.clone());
-        assert_eq!(node.borrow().children.len(), 1);
+        assert_eq!(
+            agent.select_child(&tree, ROOT, &mut StdRng::seed_from_u64(0)),
+            other
+        );
+    }
 
-        let node_ref = node.borrow();
-        let child = node_ref.children.first().unwrap();
-        assert!(child.borrow().node_move.is_some());
+    #[test]
+    fn pick_best_move_prefers_a_proven_win_over_a_better_record() {
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let mut tree = Tree::new(game);
+        let winning = place_piece(&mut tree, 0);
+        let popular = place_piece(&mut tree, 1);
+        tree.propagate_wins(&[ROOT, winning], Some(Player::Opponent));
+        tree.propagate_wins(&[ROOT, popular], Some(Player::Agent));
+        tree[winning].proven = Some(Proven::Win(Player::Agent));
+
+        let agent = Agent::new(5, 1.0);
+        assert_eq!(agent.pick_best_move(&tree).position, 0);
     }
 
     #[test]
-    fn simulate_random_game_returns_the_winning_player() {
+    fn choose_piece_needs_no_search_for_the_opening() {
+        let agent = Agent::new(1, 1.5);
+        let piece = agent.choose_piece(new_board()).unwrap();
+        assert!((0..16).contains(&piece));
+    }
+
+    #[test]
+    fn choose_piece_never_hands_over_a_winning_piece_when_there_is_another() {
+        // Pieces 0, 2 and 4 all have their first and last bits unset, so only
+        // the pieces with both set are safe to give away.
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[1] = Some(2);
+        board[2] = Some(4);
+        let agent = AgentBuilder::new(1.5).num_rounds(2000).seed(3).build();
+        let piece = agent.choose_piece(board).unwrap();
+        assert!([9, 11, 13, 15].contains(&piece), "handed over {}", piece);
+    }
+
+    #[test]
+    fn choose_piece_keeps_to_its_time_budget_when_solving() {
+        // Far too many empty squares to solve each piece in its share.
+        let mut board = new_board();
+        board[0] = Some(1);
+        board[5] = Some(6);
+        let agent = AgentBuilder::new(1.0)
+            .time_budget(Duration::from_millis(50))
+            .solver_threshold(16)
+            .build();
+        let started_at = Instant::now();
+        let piece = agent.choose_piece(board).unwrap();
+        assert!(started_at.elapsed() < Duration::from_millis(1000));
+        assert!(![1, 6].contains(&piece));
+
+        let split = AgentBuilder::new(1.0)
+            .time_budget(Duration::from_millis(10))
+            .build()
+            .with_budget_split(14);
+        assert_eq!(split.time_budget, Some(MIN_TIME_SHARE));
+    }
+
+    #[test]
+    fn choose_piece_rejects_a_board_with_nothing_left_to_give() {
+        let mut board = new_board();
+        board[0] = Some(1);
+        board[1] = Some(3);
+        board[2] = Some(5);
+        board[3] = Some(9);
+        let agent = Agent::new(10, 1.5);
+        assert_eq!(agent.choose_piece(board), Err(GameError::GameOver));
+    }
+
+    #[test]
+    fn search_blunders_as_often_as_it_is_told_to() {
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[1] = Some(2);
+        board[2] = Some(4);
+        let game = GameState::new(board, 8, Player::Agent);
+
+        let never = AgentBuilder::new(1.5).num_rounds(10).build();
+        let winning_move = never.search(game.clone()).selected_move;
+        assert_eq!(winning_move.position, 3);
+
+        let always = AgentBuilder::new(1.5)
+            .num_rounds(10)
+            .blunder_probability(1.0)
+            .build();
+        let report = always.search(game.clone());
+        assert_ne!(report.selected_move, winning_move);
+        assert!(game.legal_moves().contains(&report.selected_move));
+        assert_eq!(report.outcome, None);
+    }
+
+    #[test]
+    fn pick_best_move_takes_a_sure_draw_over_a_likely_loss() {
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let mut tree = Tree::new(game);
+        let drawing = place_piece(&mut tree, 0);
+        let risky = place_piece(&mut tree, 1);
+        for round in 0..10 {
+            tree.propagate_wins(&[ROOT, drawing], None);
+            let winner = if round < 3 {
+                Player::Agent
+            } else {
+                Player::Opponent
+            };
+            tree.propagate_wins(&[ROOT, risky], Some(winner));
+        }
+
+        let agent = Agent::new(5, 1.0);
+        assert_eq!(agent.pick_best_move(&tree).position, 0);
+
+        // The same visits, so the value decides.
+        let all_or_nothing = AgentBuilder::new(1.0).draw_reward(0.0).build();
+        assert_eq!(all_or_nothing.pick_best_move(&tree).position, 1);
+    }
+
+    #[test]
+    fn robust_max_searches_on_until_visits_and_value_agree() {
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let agent = AgentBuilder::new(1.5)
+            .num_rounds(300)
+            .move_selection(MoveSelection::RobustMax(5000))
+            .seed(2)
+            .build();
+        let mut tree = Tree::new(game);
+        let report = agent.search_tree(&mut tree);
+
+        assert!(report.rounds >= 300);
+        assert!(report.rounds < 5300);
+        assert_eq!(report.rounds as i32, tree[ROOT].num_rollouts);
+        assert!(visits_and_value_agree(&tree, DEFAULT_DRAW_REWARD));
+    }
+
+    #[test]
+    fn robust_max_keeps_to_the_grace_period_of_the_time_budget() {
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let agent = AgentBuilder::new(1.5)
+            .time_budget(Duration::from_millis(50))
+            .move_selection(MoveSelection::RobustMax(u32::MAX))
+            .build();
+        let report = agent.search(game.clone());
+
+        assert!(report.elapsed < Duration::from_millis(500));
+        assert!(game.legal_moves().contains(&report.selected_move));
+    }
+
+    #[test]
+    fn add_child_for_random_step_adds_new_node_to_tree() {
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let mut tree = Tree::new(game);
+        let agent = Agent::new(5, 1.0);
+        let child = agent.add_child_for_random_step(&mut tree, ROOT, &mut StdRng::seed_from_u64(0));
+        assert_eq!(tree[ROOT].children, vec![child]);
+        assert!(matches!(tree[child].step, Some(Step::Place(_))));
+    }
+
+    #[test]
+    fn simulate_game_returns_the_winning_player() {
         let mut board = new_board();
         board[1] = Some(1);
         board[2] = Some(2);
         board[3] = Some(3);
-        let game = GameState::new(board, 0, AGENT);
+        let game = GameState::new(board, 0, Player::Agent);
         let agent = Agent::new(5, 1.0);
 
-        assert!(agent.simulate_random_game(&game).is_some());
+        assert!(agent
+            .simulate_game(&game, &mut StdRng::seed_from_u64(0), &mut Vec::new())
+            .is_some());
+    }
+
+    // A board with no four in a row, with the pieces at `empty` taken off.
+    fn draw_board_without(empty: &[usize]) -> Board {
+        let mut board = [7, 8, 5, 10, 12, 3, 14, 1, 15, 13, 9, 6, 2, 11, 4, 0].map(Some);
+        for &position in empty {
+            board[position] = None;
+        }
+        board
+    }
+
+    // The chances of the agent winning, the opponent winning and a draw when
+    // both players move at random from game, worked out move by move.
+    fn random_play_odds(game: &GameState) -> [f64; 3] {
+        if game.is_over() {
+            return match game.winner() {
+                Some(Player::Agent) => [1.0, 0.0, 0.0],
+                Some(Player::Opponent) => [0.0, 1.0, 0.0],
+                None => [0.0, 0.0, 1.0],
+            };
+        }
+        let legal_moves = game.legal_moves();
+        let mut odds = [0.0; 3];
+        for legal_move in &legal_moves {
+            let move_odds = random_play_odds(&game.apply_move(legal_move));
+            for (odds, move_odds) in odds.iter_mut().zip(move_odds) {
+                *odds += move_odds / legal_moves.len() as f64;
+            }
+        }
+        odds
+    }
+
+    fn rollout_odds(game: &GameState, rollouts: u32) -> [f64; 3] {
+        let agent = Agent::new(1, 1.0);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counts = [0; 3];
+        for _ in 0..rollouts {
+            let outcome = match agent.simulate_game(game, &mut rng, &mut Vec::new()) {
+                Some(Player::Agent) => 0,
+                Some(Player::Opponent) => 1,
+                None => 2,
+            };
+            counts[outcome] += 1;
+        }
+        counts.map(|count| count as f64 / rollouts as f64)
+    }
+
+    fn assert_rollouts_match_random_play(game: GameState) {
+        let expected = random_play_odds(&game);
+        let sampled = rollout_odds(&game, 20000);
+        for (expected, sampled) in expected.iter().zip(sampled) {
+            assert!(
+                (expected - sampled).abs() < 0.015,
+                "expected {:?}, rollouts gave {:?}",
+                random_play_odds(&game),
+                rollout_odds(&game, 20000)
+            );
+        }
+    }
+
+    #[test]
+    fn rollouts_credit_the_player_who_completes_four_in_a_row() {
+        // Piece 11 on the last square makes four in a row.
+        let mut board = draw_board_without(&[15]);
+        board[13] = Some(0);
+        let game = GameState::new(board, 11, Player::Agent);
+        assert_eq!(rollout_odds(&game, 100), [1.0, 0.0, 0.0]);
+
+        let game = GameState::new(board, 11, Player::Opponent);
+        assert_eq!(rollout_odds(&game, 100), [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn rollouts_that_fill_the_board_without_four_in_a_row_are_draws() {
+        let game = GameState::new(draw_board_without(&[15]), 0, Player::Agent);
+        assert_eq!(rollout_odds(&game, 100), [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn rollouts_match_the_odds_of_random_play() {
+        let game = GameState::new(draw_board_without(&[0, 5, 10, 15]), 7, Player::Agent);
+        assert_rollouts_match_random_play(game);
+
+        let empty = [0, 1, 2, 3, 5, 6];
+        let game = GameState::new(draw_board_without(&empty), 8, Player::Opponent);
+        assert_rollouts_match_random_play(game);
     }
 
     #[test]
     fn select_child_works() {
-        let game = GameState::new(new_board(), 0, AGENT);
-        let node = NodeBuilder::new(game.clone()).build();
-        let child_one = NodeBuilder::new(game.clone()).build();
-        let child_two = NodeBuilder::new(game.clone()).build();
-        let child_three = NodeBuilder::new(game).build();
-
-        let mut win_counts = HashMap::new();
-        win_counts.insert(AGENT, 3);
-
-        child_one.borrow_mut().num_rollouts = 5;
-        child_one.borrow_mut().win_counts = win_counts.clone();
-        child_two.borrow_mut().num_rollouts = 4;
-        child_two.borrow_mut().win_counts = win_counts.clone();
-        child_three.borrow_mut().num_rollouts = 3;
-        child_three.borrow_mut().win_counts = win_counts.clone();
-        node.borrow_mut().children = vec![child_one, child_two, child_three];
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let mut tree = Tree::new(game);
+        let child_one = place_piece(&mut tree, 0);
+        let child_two = place_piece(&mut tree, 1);
+        let child_three = place_piece(&mut tree, 2);
+
+        let win_counts = [3, 0];
+
+        tree[child_one].num_rollouts = 5;
+        tree[child_one].win_counts = win_counts;
+        tree[child_two].num_rollouts = 4;
+        tree[child_two].win_counts = win_counts;
+        tree[child_three].num_rollouts = 3;
+        tree[child_three].win_counts = win_counts;
 
         let agent = Agent::new(5, 1.0);
-        let child = agent.select_child(node.clone());
-        assert_eq!(child.borrow().num_rollouts, 3);
+        let child = agent.select_child(&tree, ROOT, &mut StdRng::seed_from_u64(0));
+        assert_eq!(tree[child].num_rollouts, 3);
+    }
+
+    #[test]
+    fn search_stops_after_the_round_cap() {
+        let agent = Agent::new(25, 1.0);
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let report = agent.search(game);
+        assert_eq!(report.rounds, 25);
+    }
+
+    #[test]
+    fn search_stops_when_the_time_budget_runs_out() {
+        let agent = AgentBuilder::new(1.0)
+            .time_budget(Duration::from_millis(50))
+            .build();
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let report = agent.search(game);
+        assert!(report.rounds > 0);
+        assert!(report.elapsed >= Duration::from_millis(50));
+        assert!(report.elapsed < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn search_with_no_time_at_all_still_picks_a_move() {
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        for num_threads in [1, 2] {
+            let agent = AgentBuilder::new(1.0)
+                .time_budget(Duration::ZERO)
+                .num_threads(num_threads)
+                .build();
+            let report = agent.search(game.clone());
+            assert!(report.rounds >= 1);
+            assert!(game.legal_moves().contains(&report.selected_move));
+        }
+    }
+
+    #[test]
+    fn search_samples_when_the_solver_runs_out_of_time() {
+        // Far too many empty squares to solve in the time.
+        let mut board = new_board();
+        board[0] = Some(1);
+        board[5] = Some(6);
+        let game = GameState::new(board, 2, Player::Agent);
+        let agent = AgentBuilder::new(1.0)
+            .time_budget(Duration::from_millis(50))
+            .solver_threshold(16)
+            .build();
+        let report = agent.search(game.clone());
+
+        assert!(report.elapsed < Duration::from_millis(500));
+        assert!(report.rounds > 0);
+        assert_eq!(report.outcome, None);
+        assert!(game.legal_moves().contains(&report.selected_move));
+    }
+
+    #[test]
+    fn search_stops_at_whichever_limit_comes_first() {
+        let agent = AgentBuilder::new(1.0)
+            .num_rounds(10)
+            .time_budget(Duration::from_secs(60))
+            .build();
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let report = agent.search(game);
+        assert_eq!(report.rounds, 10);
+        assert!(report.elapsed < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn search_does_no_rounds_when_given_a_winning_move() {
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[1] = Some(2);
+        board[2] = Some(4);
+        let game = GameState::new(board, 8, Player::Agent);
+        let report = Agent::new(30, 1.0).search(game);
+        assert_eq!(report.rounds, 0);
+        assert_eq!(report.selected_move.position, 3);
+    }
+
+    #[test]
+    fn search_tree_keeps_the_statistics_already_in_the_tree() {
+        let agent = Agent::new(20, 1.0);
+        let mut tree = Tree::new(GameState::new(new_board(), 0, Player::Agent));
+        agent.search_tree(&mut tree);
+        let report = agent.search_tree(&mut tree);
+        assert_eq!(report.rounds, 20);
+        assert_eq!(tree[ROOT].num_rollouts, 40);
+    }
+
+    #[test]
+    fn search_solves_nearly_full_boards_exactly() {
+        // Only position 3 with piece 11 handed over avoids a loss.
+        let board = [
+            Some(5),
+            None,
+            Some(13),
+            None,
+            Some(4),
+            Some(3),
+            Some(6),
+            Some(10),
+            Some(9),
+            Some(1),
+            Some(14),
+            Some(2),
+            Some(7),
+            Some(12),
+            None,
+            Some(15),
+        ];
+        let game = GameState::new(board, 8, Player::Agent);
+        let report = Agent::new(30, 1.0).search(game);
+        assert_eq!(report.rounds, 0);
+        assert_eq!(report.outcome, Some(Outcome::Draw));
+        assert_eq!(report.selected_move.position, 3);
+        assert_eq!(report.selected_move.next_piece, 11);
+    }
+
+    #[test]
+    fn search_samples_when_the_solver_is_turned_off() {
+        let board = [
+            Some(5),
+            None,
+            Some(13),
+            None,
+            Some(4),
+            Some(3),
+            Some(6),
+            Some(10),
+            Some(9),
+            Some(1),
+            Some(14),
+            Some(2),
+            Some(7),
+            Some(12),
+            None,
+            Some(15),
+        ];
+        let game = GameState::new(board, 8, Player::Agent);
+        let agent = AgentBuilder::new(1.0)
+            .num_rounds(100)
+            .solver_threshold(0)
+            .build();
+        let report = agent.search(game);
+        assert!(report.rounds > 0);
+        // Sampling proves the draw too, once it has seen every way the game goes.
+        assert!(report.rounds < 100, "took {} rounds", report.rounds);
+        assert_eq!(report.outcome, Some(Outcome::Draw));
+        assert_eq!(report.selected_move.position, 3);
+        assert_eq!(report.selected_move.next_piece, 11);
+    }
+
+    #[test]
+    fn search_runs_the_rounds_on_every_thread() {
+        let agent = AgentBuilder::new(1.0).num_rounds(20).num_threads(3).build();
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let report = agent.search(game.clone());
+        assert_eq!(report.rounds, 60);
+        assert!(game.legal_moves().contains(&report.selected_move));
+    }
+
+    #[test]
+    fn merge_root_children_sums_statistics_of_the_same_steps() {
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let mut first_tree = Tree::new(game.clone());
+        let first_child = place_piece(&mut first_tree, 0);
+        let second_child = place_piece(&mut first_tree, 5);
+        let first_given = first_tree.add_child(first_child, Step::Give(1));
+        first_tree[first_child].num_rollouts = 4;
+        first_tree[first_child].win_counts[Player::Agent.index()] = 2;
+        first_tree[first_given].num_rollouts = 3;
+        first_tree[second_child].num_rollouts = 1;
+        first_tree[second_child].win_counts[Player::Agent.index()] = 1;
+
+        let mut second_tree = Tree::new(game.clone());
+        let same_child = place_piece(&mut second_tree, 0);
+        let same_given = second_tree.add_child(same_child, Step::Give(1));
+        let other_given = second_tree.add_child(same_child, Step::Give(2));
+        second_tree[same_child].num_rollouts = 5;
+        second_tree[same_child].win_counts[Player::Agent.index()] = 3;
+        second_tree[same_given].num_rollouts = 2;
+        second_tree[other_given].num_rollouts = 1;
+
+        let mut merged = Tree::new(game.clone());
+        merge_root_children(&mut merged, &first_tree);
+        merge_root_children(&mut merged, &second_tree);
+
+        assert_eq!(merged[ROOT].num_rollouts, 10);
+        assert_eq!(merged[ROOT].children.len(), 2);
+        let merged_child = merged.child_for_step(ROOT, Step::Place(0)).unwrap();
+        assert_eq!(merged[merged_child].num_rollouts, 9);
+        assert_eq!(merged[merged_child].wins(Player::Agent), 5);
+        assert_eq!(merged[merged_child].game_state, game.place(0));
+
+        let given_rollouts: Vec<i32> = merged[merged_child]
+            .children
+            .iter()
+            .map(|&given| merged[given].num_rollouts)
+            .collect();
+        assert_eq!(given_rollouts, vec![5, 1]);
+    }
+
+    #[test]
+    fn search_with_rave_gathers_all_moves_as_first_statistics() {
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let agent = AgentBuilder::new(1.5)
+            .num_rounds(300)
+            .rave(RaveSchedule::Equivalence(300.0))
+            .seed(5)
+            .build();
+        let mut tree = Tree::new(game.clone());
+        agent.search_tree(&mut tree);
+
+        // Every rollout through a child counts for it, and so do the ones that
+        // only placed the piece on its square later.
+        for &child in &tree[ROOT].children {
+            assert!(tree[child].amaf_rollouts >= tree[child].num_rollouts);
+        }
+        assert!(tree[ROOT]
+            .children
+            .iter()
+            .any(|&child| tree[child].amaf_rollouts > tree[child].num_rollouts));
+
+        let mut plain_tree = Tree::new(game);
+        Agent::new(300, 1.5).search_tree(&mut plain_tree);
+        assert!(plain_tree[ROOT]
+            .children
+            .iter()
+            .all(|&child| plain_tree[child].amaf_rollouts == 0));
+    }
+
+    #[test]
+    fn estimated_reward_leans_on_amaf_until_the_child_has_visits_of_its_own() {
+        let mut tree = Tree::new(GameState::new(new_board(), 0, Player::Agent));
+        let child = place_piece(&mut tree, 0);
+        tree[child].num_rollouts = 1;
+        tree[child].win_counts[Player::Opponent.index()] = 1;
+        tree[child].amaf_rollouts = 50;
+        tree[child].amaf_win_counts[Player::Agent.index()] = 50;
+
+        let plain = Agent::new(5, 1.0);
+        let rave = AgentBuilder::new(1.0)
+            .rave(RaveSchedule::Equivalence(300.0))
+            .build();
+        assert_eq!(plain.estimated_reward(&tree[child], Player::Agent), 0.0);
+        assert!(rave.estimated_reward(&tree[child], Player::Agent) > 0.9);
+
+        tree[child].num_rollouts = 100_000;
+        tree[child].win_counts[Player::Opponent.index()] = 100_000;
+        assert!(rave.estimated_reward(&tree[child], Player::Agent) < 0.1);
+    }
+
+    #[test]
+    fn every_selection_policy_finds_a_legal_move_and_repeats_with_a_seed() {
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let agents = [
+            AgentBuilder::new(1.0).selection_policy(Ucb1),
+            AgentBuilder::new(1.0).selection_policy(Ucb1Tuned),
+            AgentBuilder::new(1.0).selection_policy(Puct::default()),
+            AgentBuilder::new(1.0).selection_policy(ThompsonSampling),
+        ];
+        for builder in agents {
+            let agent = builder.num_rounds(300).seed(3).build();
+            let report = agent.search(game.clone());
+            assert!(game.legal_moves().contains(&report.selected_move));
+            assert_eq!(
+                agent.search(game.clone()).selected_move,
+                report.selected_move
+            );
+        }
+    }
+
+    #[test]
+    fn puct_gives_every_child_its_prior() {
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let agent = AgentBuilder::new(1.0)
+            .num_rounds(50)
+            .selection_policy(Puct::default())
+            .seed(3)
+            .build();
+        let mut tree = Tree::new(game);
+        agent.search_tree(&mut tree);
+
+        // Nothing wins on an empty board, so all 16 squares look alike.
+        for &child in &tree[ROOT].children {
+            assert_eq!(tree[child].prior, 1.0 / 16.0);
+        }
+    }
+
+    #[test]
+    fn ponder_stops_when_told_to_or_out_of_budget() {
+        let game = GameState::new(new_board(), 0, Player::Opponent);
+        let agent = Agent::new(200, 1.5);
+
+        let mut tree = Tree::new(game.clone());
+        assert_eq!(agent.ponder(&mut tree, &AtomicBool::new(false)), 200);
+        assert_eq!(tree[ROOT].num_rollouts, 200);
+
+        let mut tree = Tree::new(game);
+        assert_eq!(agent.ponder(&mut tree, &AtomicBool::new(true)), 0);
+        assert_eq!(tree.size(), 1);
+    }
+
+    #[test]
+    fn a_shared_node_keeps_the_prior_from_its_first_parent() {
+        use Step::{Give, Place};
+        let agent = AgentBuilder::new(1.0)
+            .selection_policy(Puct::default())
+            .build();
+        let mut tree = Tree::with_transpositions(GameState::new(new_board(), 0, Player::Agent));
+        // Pieces 0, 1 and 2 end up on squares 0, 5 and 10 either way.
+        let add_line = |tree: &mut Tree, steps: [Step; 6]| {
+            steps.into_iter().fold(ROOT, |node, step| {
+                tree.child_for_step(node, step)
+                    .unwrap_or_else(|| agent.add_child_with_prior(tree, node, step))
+            })
+        };
+        let shared = add_line(
+            &mut tree,
+            [Place(0), Give(1), Place(5), Give(2), Place(10), Give(3)],
+        );
+        tree[shared].prior = 0.42;
+        let reached_again = add_line(
+            &mut tree,
+            [Place(0), Give(2), Place(10), Give(1), Place(5), Give(3)],
+        );
+
+        assert_eq!(reached_again, shared);
+        assert_eq!(tree[shared].prior, 0.42);
     }
 
     #[test]
     fn select_move_returns_a_move() {
         let agent = Agent::new(5, 1.0);
-        let game = GameState::new(new_board(), 0, AGENT);
+        let game = GameState::new(new_board(), 0, Player::Agent);
         let next_move = agent.select_move(game);
         assert!(next_move.next_piece > -1);
     }
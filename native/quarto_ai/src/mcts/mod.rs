@@ -1,5 +1,18 @@
 mod agent;
+mod move_selection;
 mod node;
+mod rave;
+mod report;
+mod rollout;
+mod selection;
 
-pub use agent::Agent;
-pub use node::{Node, NodeBuilder, AGENT, OPPONENT};
+pub use agent::{Agent, AgentBuilder};
+pub use move_selection::MoveSelection;
+pub use node::{MCTNode, NodeId, Proven, Tree, ROOT};
+pub use rave::RaveSchedule;
+pub use report::{ChildReport, SearchReport};
+pub use rollout::{RolloutPolicy, SafeRollout, UniformRollout, WinningRollout};
+pub use selection::{ChildStats, SelectionPolicy, Ucb1};
+// No preset searches with these yet; they are there to tune the presets with.
+#[allow(unused_imports)]
+pub use selection::{tactical_prior, uniform_prior, Puct, ThompsonSampling, Ucb1Tuned};
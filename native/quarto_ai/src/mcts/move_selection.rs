@@ -0,0 +1,149 @@
+use super::agent::is_proven_loss;
+use super::{NodeId, Proven, Tree, ROOT};
+
+// How the search picks a child once it is done searching. A proven win is
+// always taken, and a proven loss only when every child is one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MoveSelection {
+    // The most visited child, which the search kept coming back to. Ties go to
+    // the better mean reward.
+    MaxVisits,
+    // The best mean reward, however few rollouts it rests on.
+    MaxValue,
+    // The best lower confidence bound, mean - a / sqrt(visits), so a child
+    // visited a handful of times needs a much better record to be picked.
+    #[allow(dead_code)] // No preset picks its move this way yet.
+    SecureChild(f64),
+    // The most visited child once it also has the best mean reward, for the
+    // square and for the piece. Searching carries on past the budget, for at
+    // most this many rounds, until they agree. With a time budget it never
+    // goes on for more than half as long again as the budget.
+    #[allow(dead_code)] // No preset picks its move this way yet.
+    RobustMax(u32),
+}
+
+impl MoveSelection {
+    pub(super) fn pick(self, tree: &Tree, node: NodeId, draw_reward: f64) -> NodeId {
+        let player = tree[node].game_state.current_player;
+        let children = &tree[node].children;
+        if let Some(&winning) = children
+            .iter()
+            .find(|&&child| tree[child].proven == Some(Proven::Win(player)))
+        {
+            return winning;
+        }
+
+        let value = |child: NodeId| {
+            let child = &tree[child];
+            if child.num_rollouts > 0 {
+                child.mean_reward(player, draw_reward)
+            } else {
+                draw_reward
+            }
+        };
+        let visits = |child: NodeId| tree[child].num_rollouts as f64;
+        let score = |child: NodeId| match self {
+            MoveSelection::MaxVisits | MoveSelection::RobustMax(_) => visits(child),
+            MoveSelection::MaxValue => value(child),
+            MoveSelection::SecureChild(confidence) => {
+                value(child) - confidence / visits(child).sqrt()
+            }
+        };
+
+        let candidates: Vec<NodeId> = children
+            .iter()
+            .copied()
+            .filter(|&child| !is_proven_loss(tree, node, child))
+            .collect();
+        if candidates.is_empty() {
+            // Every child loses: hold out where the search looked hardest.
+            return children
+                .iter()
+                .copied()
+                .max_by(|&a, &b| visits(a).total_cmp(&visits(b)))
+                .expect("Nothing was searched");
+        }
+
+        candidates
+            .into_iter()
+            .max_by(|&a, &b| {
+                score(a)
+                    .total_cmp(&score(b))
+                    .then(value(a).total_cmp(&value(b)))
+            })
+            .expect("Nothing was searched")
+    }
+}
+
+// Whether the most visited square is also the best valued one, and the same
+// goes for the piece handed over from it.
+pub(super) fn visits_and_value_agree(tree: &Tree, draw_reward: f64) -> bool {
+    let agree = |node: NodeId| {
+        MoveSelection::MaxVisits.pick(tree, node, draw_reward)
+            == MoveSelection::MaxValue.pick(tree, node, draw_reward)
+    };
+    if tree[ROOT].children.is_empty() || !agree(ROOT) {
+        return false;
+    }
+    let placed = MoveSelection::MaxVisits.pick(tree, ROOT, draw_reward);
+    tree[placed].children.is_empty() || agree(placed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{new_board, GameState, Player, Step};
+
+    // A child visited once and won, and one that won 60 of 100.
+    fn lucky_and_steady() -> (Tree, NodeId, NodeId) {
+        let mut tree = Tree::new(GameState::new(new_board(), 0, Player::Agent));
+        let lucky = tree.add_child(ROOT, Step::Place(0));
+        let steady = tree.add_child(ROOT, Step::Place(1));
+        tree.propagate_wins(&[ROOT, lucky], Some(Player::Agent));
+        for round in 0..100 {
+            let winner = if round < 60 {
+                Player::Agent
+            } else {
+                Player::Opponent
+            };
+            tree.propagate_wins(&[ROOT, steady], Some(winner));
+        }
+        (tree, lucky, steady)
+    }
+
+    #[test]
+    fn max_value_falls_for_a_lucky_child_and_the_others_do_not() {
+        let (tree, lucky, steady) = lucky_and_steady();
+        assert_eq!(MoveSelection::MaxValue.pick(&tree, ROOT, 0.5), lucky);
+        assert_eq!(MoveSelection::MaxVisits.pick(&tree, ROOT, 0.5), steady);
+        assert_eq!(
+            MoveSelection::SecureChild(1.0).pick(&tree, ROOT, 0.5),
+            steady
+        );
+        assert!(!visits_and_value_agree(&tree, 0.5));
+    }
+
+    #[test]
+    fn every_strategy_takes_a_proven_win() {
+        let (mut tree, lucky, steady) = lucky_and_steady();
+        tree[lucky].proven = Some(Proven::Win(Player::Agent));
+        for strategy in [
+            MoveSelection::MaxVisits,
+            MoveSelection::MaxValue,
+            MoveSelection::SecureChild(1.0),
+        ] {
+            assert_eq!(strategy.pick(&tree, ROOT, 0.5), lucky);
+        }
+
+        tree[lucky].proven = Some(Proven::Win(Player::Opponent));
+        assert_eq!(MoveSelection::MaxValue.pick(&tree, ROOT, 0.5), steady);
+    }
+
+    #[test]
+    fn when_every_child_loses_the_most_visited_one_is_picked() {
+        let (mut tree, lucky, steady) = lucky_and_steady();
+        tree[lucky].proven = Some(Proven::Win(Player::Opponent));
+        tree[steady].proven = Some(Proven::Win(Player::Opponent));
+        assert_eq!(MoveSelection::MaxValue.pick(&tree, ROOT, 0.5), steady);
+    }
+}
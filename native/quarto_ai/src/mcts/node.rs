@@ -1,45 +1,291 @@
-use crate::game::{GameState, Move};
+use crate::game::{GameState, Move, Player, Step};
+use crate::solver::Outcome;
 use rand::Rng;
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
-use std::rc::{Rc, Weak};
-
-pub const OPPONENT: &'static str = "opponent";
-pub const AGENT: &'static str = "agent";
-
-pub type Node = Rc<RefCell<MCTNode>>;
-
-pub struct NodeBuilder {
-    pub game_state: GameState,
-    pub parent: Option<Weak<RefCell<MCTNode>>>,
-    pub node_move: Option<Move>,
+use std::ops::{Index, IndexMut};
+
+pub type NodeId = u32;
+pub const ROOT: NodeId = 0;
+
+// Arena of every node in a search tree. Nodes point at each other by index, so
+// growing the tree is a push onto one Vec instead of an allocation per node.
+//
+// Each turn takes two levels of the tree: placing the active piece, then
+// handing over the next one. Every square and every piece gets statistics of
+// its own, instead of every pairing of the two.
+//
+// With a transposition table the tree becomes a DAG: a move into a game that is
+// already in the tree links to the existing node instead of adding a new one,
+// so every way of reaching a game shares its statistics.
+#[derive(Clone)]
+pub struct Tree {
+    nodes: Vec<MCTNode>,
+    transpositions: Option<HashMap<u64, NodeId>>,
 }
 
-impl NodeBuilder {
+impl Tree {
     pub fn new(game_state: GameState) -> Self {
         Self {
-            game_state,
-            parent: None,
-            node_move: None,
+            nodes: vec![MCTNode::new(game_state)],
+            transpositions: None,
+        }
+    }
+
+    pub fn with_transpositions(game_state: GameState) -> Self {
+        let transpositions = HashMap::from([(game_state.hash(), ROOT)]);
+        Self {
+            nodes: vec![MCTNode::new(game_state)],
+            transpositions: Some(transpositions),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn add_child(&mut self, parent: NodeId, step: Step) -> NodeId {
+        let game_state = self[parent].game_state.apply_step(step);
+
+        if let Some(existing) = self.transposition(&game_state) {
+            self[parent].children.push(existing);
+            return existing;
+        }
+
+        let mut child = MCTNode::new(game_state);
+        child.parent = Some(parent);
+        child.step = Some(step);
+        self.push_child(parent, child)
+    }
+
+    pub fn child_for_step(&self, node: NodeId, step: Step) -> Option<NodeId> {
+        // Compare games rather than steps, since a node only keeps the step from
+        // the first parent of a shared node.
+        let game_state = self[node].game_state.apply_step(step);
+        self[node]
+            .children
+            .iter()
+            .copied()
+            .find(|&child| self[child].game_state == game_state)
+    }
+
+    // The node two levels down that a whole move leads to, or one level down
+    // when placing the piece ends the game.
+    pub fn child_for_move(&self, node: NodeId, node_move: &Move) -> Option<NodeId> {
+        let placed = self.child_for_step(node, Step::Place(node_move.position))?;
+        if self[placed].is_terminal() {
+            return Some(placed);
+        }
+        self.child_for_step(placed, Step::Give(node_move.next_piece))
+    }
+
+    // The step that takes parent's game to child's. Only the first parent of a
+    // shared node finds it in step.
+    pub fn step_between(&self, parent: NodeId, child: NodeId) -> Step {
+        if self[child].parent == Some(parent) {
+            if let Some(step) = self[child].step {
+                return step;
+            }
+        }
+        self[parent]
+            .game_state
+            .legal_steps()
+            .into_iter()
+            .find(|&step| self[parent].game_state.apply_step(step) == self[child].game_state)
+            .expect("Child is not reachable from parent")
+    }
+
+    // Every whole move tried from node, with the node it leads to: each square
+    // the piece was placed on, paired with each piece handed over after it.
+    // Placements that ended the game stand on their own, handing over nothing.
+    pub fn explored_moves(&self, node: NodeId) -> Vec<(Move, NodeId)> {
+        let Some(piece) = self[node].game_state.active_piece() else {
+            return Vec::new();
+        };
+
+        let mut moves = Vec::new();
+        for &placed in &self[node].children {
+            let Step::Place(position) = self.step_between(node, placed) else {
+                continue;
+            };
+            let whole_move = |next_piece| Move {
+                position,
+                piece,
+                next_piece,
+            };
+
+            if self[placed].is_terminal() {
+                moves.push((whole_move(0), placed));
+            }
+            for &given in &self[placed].children {
+                if let Step::Give(next_piece) = self.step_between(placed, given) {
+                    moves.push((whole_move(next_piece), given));
+                }
+            }
+        }
+        moves
+    }
+
+    // Breadth first search for the node holding this game, at most max_depth
+    // steps below the root.
+    pub fn find(&self, game_state: &GameState, max_depth: usize) -> Option<NodeId> {
+        let mut level = vec![ROOT];
+        for depth in 0..=max_depth {
+            if let Some(&found) = level.iter().find(|&&id| self[id].game_state == *game_state) {
+                return Some(found);
+            }
+            if depth < max_depth {
+                level = level
+                    .iter()
+                    .flat_map(|&id| self[id].children.iter().copied())
+                    .collect();
+            }
+        }
+        None
+    }
+
+    // Copy the branch below node into a tree of its own, with node as the root.
+    // Everything outside the branch is left behind.
+    pub fn subtree(&self, node: NodeId) -> Tree {
+        let mut root = self[node].clone();
+        root.parent = None;
+        root.step = None;
+        root.children = Vec::new();
+        let mut subtree = match self.transpositions {
+            Some(_) => Tree::with_transpositions(root.game_state.clone()),
+            None => Tree::new(root.game_state.clone()),
+        };
+        subtree[ROOT] = root;
+
+        // Shared nodes are copied once, and linked from every copied parent.
+        let mut copied = HashMap::from([(node, ROOT)]);
+        let mut pending = vec![node];
+        while let Some(old_id) = pending.pop() {
+            let new_id = copied[&old_id];
+            for &old_child in &self[old_id].children {
+                if let Some(&new_child) = copied.get(&old_child) {
+                    subtree[new_id].children.push(new_child);
+                    continue;
+                }
+
+                let mut child = self[old_child].clone();
+                child.parent = Some(new_id);
+                child.children = Vec::new();
+                let new_child = subtree.push_child(new_id, child);
+                copied.insert(old_child, new_child);
+                pending.push(old_child);
+            }
         }
+        subtree
     }
 
-    pub fn parent(mut self, parent: Weak<RefCell<MCTNode>>) -> Self {
-        self.parent = Some(parent);
-        self
+    // Record win and propagate it back up the path the round took from the root.
+    pub fn propagate_wins(&mut self, path: &[NodeId], winner: Option<Player>) {
+        for &id in path.iter().rev() {
+            self[id].record_win(winner);
+        }
+    }
+
+    // Back the proven values of the children up into node, minimax style: the
+    // player to move wins if any move wins, and loses only once every move is
+    // known to lose.
+    pub fn prove(&mut self, node: NodeId) -> Option<Proven> {
+        if self[node].proven.is_some() {
+            return self[node].proven;
+        }
+
+        let player = self[node].game_state.current_player;
+        let children: Vec<Option<Proven>> = self[node]
+            .children
+            .iter()
+            .map(|&child| self[child].proven)
+            .collect();
+
+        let proven = if children.contains(&Some(Proven::Win(player))) {
+            Some(Proven::Win(player))
+        } else if self[node].can_add_child() || children.contains(&None) {
+            None
+        } else if children.contains(&Some(Proven::Draw)) {
+            Some(Proven::Draw)
+        } else {
+            Some(Proven::Win(player.opponent()))
+        };
+        self[node].proven = proven;
+        proven
+    }
+
+    // Prove what can be proven along the path a round took, from the bottom up.
+    pub fn prove_path(&mut self, path: &[NodeId]) {
+        for &id in path.iter().rev() {
+            if self.prove(id).is_none() {
+                break;
+            }
+        }
+    }
+
+    fn transposition(&self, game_state: &GameState) -> Option<NodeId> {
+        let existing = *self.transpositions.as_ref()?.get(&game_state.hash())?;
+        // Guard against hash collisions.
+        (self[existing].game_state == *game_state).then_some(existing)
+    }
+
+    fn push_child(&mut self, parent: NodeId, child: MCTNode) -> NodeId {
+        let child_id = self.nodes.len() as NodeId;
+        if let Some(transpositions) = &mut self.transpositions {
+            transpositions.insert(child.game_state.hash(), child_id);
+        }
+        self.nodes.push(child);
+        self[parent].children.push(child_id);
+        child_id
+    }
+}
+
+impl Index<NodeId> for Tree {
+    type Output = MCTNode;
+
+    fn index(&self, id: NodeId) -> &MCTNode {
+        &self.nodes[id as usize]
+    }
+}
+
+impl IndexMut<NodeId> for Tree {
+    fn index_mut(&mut self, id: NodeId) -> &mut MCTNode {
+        &mut self.nodes[id as usize]
+    }
+}
+
+// A result sampling can no longer change, because every way the game can go
+// from the node has been looked at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Proven {
+    Win(Player),
+    Draw,
+}
+
+impl Proven {
+    fn of_finished_game(game_state: &GameState) -> Option<Self> {
+        if !game_state.is_over() {
+            None
+        } else if game_state.has_four_in_a_row() {
+            Some(Proven::Win(game_state.last_player()))
+        } else {
+            Some(Proven::Draw)
+        }
     }
 
-    pub fn node_move(mut self, node_move: Move) -> Self {
-        self.node_move = Some(node_move);
-        self
+    pub fn winner(self) -> Option<Player> {
+        match self {
+            Proven::Win(player) => Some(player),
+            Proven::Draw => None,
+        }
     }
 
-    pub fn build(self) -> Node {
-        let mut node = MCTNode::new(self.game_state);
-        node.parent = self.parent;
-        node.node_move = self.node_move;
-        Rc::new(RefCell::new(node))
+    pub fn outcome_for(self, player: Player) -> Outcome {
+        match self {
+            Proven::Win(winner) if winner == player => Outcome::Win,
+            Proven::Win(_) => Outcome::Loss,
+            Proven::Draw => Outcome::Draw,
+        }
     }
 }
 
@@ -47,69 +293,125 @@ impl NodeBuilder {
 #[derive(Clone)]
 pub struct MCTNode {
     pub game_state: GameState,
-    pub children: Vec<Node>,
+    pub children: Vec<NodeId>,
     pub num_rollouts: i32,
-    pub unvisited_moves: Vec<Move>,
-    pub win_counts: HashMap<&'static str, i32>,
-    pub parent: Option<Weak<RefCell<MCTNode>>>,
-    pub node_move: Option<Move>,
+    // Generated the first time a child is added. Most nodes are leaves that
+    // never get that far, so they never pay for their legal steps.
+    pub unvisited_steps: Option<Vec<Step>>,
+    pub win_counts: [i32; 2], // Indexed by Player::index
+    // Rollouts where the step to this node was taken at some point, not
+    // necessarily first, for RAVE.
+    pub amaf_rollouts: i32,
+    pub amaf_win_counts: [i32; 2],
+    // How likely the selection policy thought the step to this node was
+    // before searching it.
+    pub prior: f64,
+
+    // The parent that first added the node, and the step it took to get here.
+    // Other parents can share the node when there is a transposition table.
+    pub parent: Option<NodeId>,
+    pub step: Option<Step>,
+    pub proven: Option<Proven>,
 }
 
 impl MCTNode {
     pub fn new(game_state: GameState) -> Self {
-        let mut win_counts = HashMap::new();
-        win_counts.insert(AGENT, 0);
-        win_counts.insert(OPPONENT, 0);
-
         Self {
-            unvisited_moves: game_state.legal_moves(),
+            proven: Proven::of_finished_game(&game_state),
             game_state,
-            win_counts,
+            win_counts: [0; 2],
+            amaf_rollouts: 0,
+            amaf_win_counts: [0; 2],
+            prior: 1.0,
+            unvisited_steps: None,
             children: Vec::new(),
             num_rollouts: 0,
             parent: None,
-            node_move: None,
+            step: None,
         }
     }
 
-    pub fn random_legal_move(&mut self) -> Move {
-        let mut rng = rand::thread_rng();
-        let index: usize = rng.gen_range(0..self.unvisited_moves.len());
-        self.unvisited_moves.swap_remove(index)
+    pub fn random_unvisited_step(&mut self, rng: &mut impl Rng) -> Step {
+        let unvisited_steps = self.unvisited_steps();
+        let index: usize = rng.gen_range(0..unvisited_steps.len());
+        unvisited_steps.swap_remove(index)
     }
 
     pub fn can_add_child(&self) -> bool {
-        self.unvisited_moves.len() > 0
+        match &self.unvisited_steps {
+            Some(steps) => !steps.is_empty(),
+            None => !self.is_terminal(),
+        }
     }
 
     pub fn is_terminal(&self) -> bool {
         self.game_state.is_over()
     }
 
-    pub fn winning_fraction(&self, player: &str) -> f64 {
-        let wins = *self.win_counts.get(player).unwrap() as f64;
-        wins / self.num_rollouts as f64
+    pub fn wins(&self, player: Player) -> i32 {
+        self.win_counts[player.index()]
+    }
+
+    #[allow(dead_code)] // Moves are scored by mean_reward, which counts draws too.
+    pub fn winning_fraction(&self, player: Player) -> f64 {
+        self.wins(player) as f64 / self.num_rollouts as f64
+    }
+
+    // Rollouts that neither player won.
+    pub fn num_draws(&self) -> i32 {
+        self.num_rollouts - self.win_counts.iter().sum::<i32>()
+    }
+
+    // Average score for player over the rollouts: 1 for a win, draw_reward for
+    // a draw and 0 for a loss.
+    pub fn mean_reward(&self, player: Player, draw_reward: f64) -> f64 {
+        let wins = self.wins(player) as f64;
+        (wins + draw_reward * self.num_draws() as f64) / self.num_rollouts as f64
+    }
+
+    // How far the rewards spread around mean_reward.
+    pub fn reward_variance(&self, player: Player, draw_reward: f64) -> f64 {
+        let wins = self.wins(player) as f64;
+        let mean_square =
+            (wins + draw_reward * draw_reward * self.num_draws() as f64) / self.num_rollouts as f64;
+        let mean = self.mean_reward(player, draw_reward);
+        (mean_square - mean * mean).max(0.0)
     }
 
-    pub fn record_win(&mut self, winner: Option<&'static str>) {
+    pub fn record_win(&mut self, winner: Option<Player>) {
         if let Some(player) = winner {
-            let count = match self.win_counts.get(player) {
-                Some(count) => *count,
-                None => 0,
-            };
-            self.win_counts.insert(player, count + 1);
+            self.win_counts[player.index()] += 1;
         }
         self.num_rollouts += 1;
     }
 
-    // Record win and propagate it back up the tree.
-    pub fn propagate_wins(&mut self, winner: Option<&'static str>) {
-        self.record_win(winner);
+    pub fn amaf_wins(&self, player: Player) -> i32 {
+        self.amaf_win_counts[player.index()]
+    }
 
-        if let Some(parent_node) = self.parent.clone() {
-            let parent = parent_node.upgrade().unwrap();
-            parent.borrow_mut().propagate_wins(winner);
+    // Like mean_reward, over the all-moves-as-first rollouts.
+    pub fn amaf_reward(&self, player: Player, draw_reward: f64) -> f64 {
+        let wins = self.amaf_wins(player) as f64;
+        let draws = (self.amaf_rollouts - self.amaf_win_counts.iter().sum::<i32>()) as f64;
+        (wins + draw_reward * draws) / self.amaf_rollouts as f64
+    }
+
+    pub fn record_amaf(&mut self, winner: Option<Player>) {
+        if let Some(player) = winner {
+            self.amaf_win_counts[player.index()] += 1;
         }
+        self.amaf_rollouts += 1;
+    }
+
+    fn unvisited_steps(&mut self) -> &mut Vec<Step> {
+        let game_state = &self.game_state;
+        self.unvisited_steps.get_or_insert_with(|| {
+            if game_state.is_over() {
+                Vec::new()
+            } else {
+                game_state.legal_steps()
+            }
+        })
     }
 }
 
@@ -118,9 +420,16 @@ impl fmt::Debug for MCTNode {
         f.debug_struct("MCTNode")
             .field("win_counts", &self.win_counts)
             .field("num_rollouts", &self.num_rollouts)
-            .field("move", &self.node_move)
+            .field("amaf_win_counts", &self.amaf_win_counts)
+            .field("amaf_rollouts", &self.amaf_rollouts)
+            .field("prior", &self.prior)
+            .field("step", &self.step)
+            .field("proven", &self.proven)
             .field("num_children", &self.children.len())
-            .field("num_unvisited_moves", &self.unvisited_moves.len())
+            .field(
+                "num_unvisited_steps",
+                &self.unvisited_steps.as_ref().map(Vec::len),
+            )
             .finish()
     }
 }
@@ -129,9 +438,11 @@ impl fmt::Debug for MCTNode {
 mod tests {
     use super::*;
     use crate::game::new_board;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
 
     fn setup() -> GameState {
-        GameState::new(new_board(), 0, AGENT)
+        GameState::new(new_board(), 0, Player::Agent)
     }
 
     fn setup_finished_game() -> GameState {
@@ -140,95 +451,371 @@ mod tests {
         board[1] = Some(2);
         board[2] = Some(4);
         board[3] = Some(8);
-        GameState::new(board, 15, AGENT)
+        GameState::new(board, 15, Player::Agent)
     }
 
     #[test]
     fn propagate_scores_records_win_for_every_parent_in_the_branch() {
-        let root = NodeBuilder::new(setup()).build();
-        let child = NodeBuilder::new(setup()).build();
-        let grand_child = NodeBuilder::new(setup()).build();
+        let mut tree = Tree::new(setup());
+        let child = tree.add_child(ROOT, Step::Place(0));
+        let grand_child = tree.add_child(child, Step::Give(1));
+
+        tree.propagate_wins(&[ROOT, child, grand_child], Some(Player::Agent));
+        assert_eq!(tree[grand_child].wins(Player::Agent), 1);
+        assert_eq!(tree[child].wins(Player::Agent), 1);
+        assert_eq!(tree[ROOT].wins(Player::Agent), 1);
+    }
+
+    #[test]
+    fn add_child_links_the_child_to_its_parent() {
+        let mut tree = Tree::new(setup());
+        let child = tree.add_child(ROOT, Step::Place(3));
+        assert_eq!(tree.size(), 2);
+        assert_eq!(tree[ROOT].children, vec![child]);
+        assert_eq!(tree[child].parent, Some(ROOT));
+        assert_eq!(tree[child].step, Some(Step::Place(3)));
+        assert_eq!(tree[child].game_state, setup().place(3));
+    }
+
+    // Pieces 0, 1 and 2 end up on squares 0, 5 and 10 either way, with piece 3
+    // to place next. The lines part after the first placement and meet again
+    // after the third.
+    fn transposed_lines() -> ([Step; 6], [Step; 6]) {
+        use Step::{Give, Place};
+        (
+            [Place(0), Give(1), Place(5), Give(2), Place(10), Give(3)],
+            [Place(0), Give(2), Place(10), Give(1), Place(5), Give(3)],
+        )
+    }
+
+    // Follow the line down from the root, adding the steps not in the tree yet.
+    fn add_line(tree: &mut Tree, steps: [Step; 6]) -> Vec<NodeId> {
+        let mut path = vec![ROOT];
+        for step in steps {
+            let node = *path.last().unwrap();
+            let child = tree
+                .child_for_step(node, step)
+                .unwrap_or_else(|| tree.add_child(node, step));
+            path.push(child);
+        }
+        path
+    }
+
+    #[test]
+    fn add_child_links_transpositions_to_the_existing_node() {
+        let (first_line, second_line) = transposed_lines();
+        let mut tree = Tree::with_transpositions(setup());
+        let first_path = add_line(&mut tree, first_line);
+        let second_path = add_line(&mut tree, second_line);
+
+        assert_eq!(first_path[5], second_path[5]);
+        assert_eq!(first_path[6], second_path[6]);
+        assert_eq!(tree.size(), 10);
+        assert_eq!(tree[second_path[4]].children, vec![first_path[5]]);
+    }
 
-        child.borrow_mut().parent = Some(Rc::downgrade(&root));
-        grand_child.borrow_mut().parent = Some(Rc::downgrade(&child));
-        child.borrow_mut().children.push(grand_child.clone());
-        root.borrow_mut().children.push(child.clone());
+    #[test]
+    fn add_child_keeps_transpositions_apart_without_a_table() {
+        let (first_line, second_line) = transposed_lines();
+        let mut tree = Tree::new(setup());
+        let first_path = add_line(&mut tree, first_line);
+        let second_path = add_line(&mut tree, second_line);
+
+        assert_ne!(first_path[5], second_path[5]);
+        assert_eq!(tree.size(), 12);
+    }
+
+    #[test]
+    fn propagate_wins_updates_a_shared_node_from_either_path() {
+        let (first_line, second_line) = transposed_lines();
+        let mut tree = Tree::with_transpositions(setup());
+        let first_path = add_line(&mut tree, first_line);
+        let second_path = add_line(&mut tree, second_line);
+
+        tree.propagate_wins(&first_path, Some(Player::Agent));
+        tree.propagate_wins(&second_path, Some(Player::Opponent));
+        assert_eq!(tree[first_path[6]].num_rollouts, 2);
+        assert_eq!(tree[first_path[2]].num_rollouts, 1);
+        assert_eq!(tree[second_path[2]].num_rollouts, 1);
+        assert_eq!(tree[ROOT].num_rollouts, 2);
+    }
 
-        grand_child.borrow_mut().propagate_wins(Some(AGENT));
-        assert_eq!(grand_child.borrow().win_counts.get(AGENT).unwrap(), &1);
-        assert_eq!(child.borrow().win_counts.get(AGENT).unwrap(), &1);
-        assert_eq!(root.borrow().win_counts.get(AGENT).unwrap(), &1);
+    #[test]
+    fn subtree_copies_a_shared_node_once() {
+        let (first_line, second_line) = transposed_lines();
+        let mut tree = Tree::with_transpositions(setup());
+        let first_path = add_line(&mut tree, first_line);
+        let second_path = add_line(&mut tree, second_line);
+        tree.add_child(ROOT, Step::Place(4));
+
+        let subtree = tree.subtree(ROOT);
+        assert_eq!(subtree.size(), 11);
+
+        let shared = |tree: &Tree, line: &[Step]| {
+            line.iter()
+                .fold(ROOT, |node, &step| tree.child_for_step(node, step).unwrap())
+        };
+        assert_eq!(
+            shared(&subtree, &first_line),
+            shared(&subtree, &second_line)
+        );
+        assert_eq!(first_path[6], second_path[6]);
+    }
+
+    #[test]
+    fn new_proves_a_finished_game_for_the_player_who_won_it() {
+        let node = MCTNode::new(setup_finished_game());
+        assert_eq!(node.proven, Some(Proven::Win(Player::Opponent)));
+        assert_eq!(MCTNode::new(setup()).proven, None);
+    }
+
+    #[test]
+    fn prove_finds_a_win_as_soon_as_one_move_wins() {
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[1] = Some(2);
+        board[2] = Some(4);
+        let mut tree = Tree::new(GameState::new(board, 8, Player::Agent));
+        tree.add_child(ROOT, Step::Place(5));
+        assert_eq!(tree.prove(ROOT), None);
+
+        let child = tree.add_child(ROOT, Step::Place(3));
+        assert_eq!(tree[child].proven, Some(Proven::Win(Player::Agent)));
+        tree.prove_path(&[ROOT, child]);
+        assert_eq!(tree[ROOT].proven, Some(Proven::Win(Player::Agent)));
+    }
+
+    #[test]
+    fn prove_waits_for_every_move_before_proving_a_loss() {
+        let mut tree = Tree::new(setup());
+        let first = tree.add_child(ROOT, Step::Place(0));
+        let second = tree.add_child(ROOT, Step::Place(1));
+        tree[first].proven = Some(Proven::Win(Player::Opponent));
+        tree[second].proven = Some(Proven::Win(Player::Opponent));
+        assert_eq!(tree.prove(ROOT), None);
+
+        tree[ROOT].unvisited_steps = Some(Vec::new());
+        assert_eq!(tree.prove(ROOT), Some(Proven::Win(Player::Opponent)));
+    }
+
+    #[test]
+    fn prove_loses_for_the_player_handing_over_when_every_piece_loses() {
+        let mut tree = Tree::new(setup().place(0));
+        let first = tree.add_child(ROOT, Step::Give(1));
+        let second = tree.add_child(ROOT, Step::Give(2));
+        tree[first].proven = Some(Proven::Win(Player::Opponent));
+        tree[second].proven = Some(Proven::Win(Player::Opponent));
+        tree[ROOT].unvisited_steps = Some(Vec::new());
+        assert_eq!(tree.prove(ROOT), Some(Proven::Win(Player::Opponent)));
+    }
+
+    #[test]
+    fn prove_settles_for_a_draw_when_nothing_wins() {
+        let mut tree = Tree::new(setup());
+        let first = tree.add_child(ROOT, Step::Place(0));
+        let second = tree.add_child(ROOT, Step::Place(1));
+        tree[first].proven = Some(Proven::Win(Player::Opponent));
+        tree[second].proven = Some(Proven::Draw);
+        tree[ROOT].unvisited_steps = Some(Vec::new());
+        assert_eq!(tree.prove(ROOT), Some(Proven::Draw));
+    }
+
+    #[test]
+    fn step_between_finds_the_step_from_either_parent_of_a_shared_node() {
+        let (first_line, second_line) = transposed_lines();
+        let mut tree = Tree::with_transpositions(setup());
+        let first_path = add_line(&mut tree, first_line);
+        let second_path = add_line(&mut tree, second_line);
+
+        assert_eq!(
+            tree.step_between(first_path[4], first_path[5]),
+            first_line[4]
+        );
+        assert_eq!(
+            tree.step_between(second_path[4], second_path[5]),
+            second_line[4]
+        );
+    }
+
+    fn test_move(position: i32, next_piece: i32) -> Move {
+        Move {
+            position,
+            piece: 0,
+            next_piece,
+        }
+    }
+
+    #[test]
+    fn child_for_move_finds_the_child_two_steps_down() {
+        let mut tree = Tree::new(setup());
+        let placed = tree.add_child(ROOT, Step::Place(1));
+        let given = tree.add_child(placed, Step::Give(2));
+        assert_eq!(tree.child_for_step(ROOT, Step::Place(1)), Some(placed));
+        assert_eq!(tree.child_for_move(ROOT, &test_move(1, 2)), Some(given));
+        assert_eq!(tree.child_for_move(ROOT, &test_move(1, 3)), None);
+        assert_eq!(tree.child_for_move(ROOT, &test_move(2, 2)), None);
+    }
+
+    #[test]
+    fn explored_moves_pair_each_placement_with_the_pieces_handed_over() {
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[1] = Some(2);
+        board[2] = Some(4);
+        let mut tree = Tree::new(GameState::new(board, 8, Player::Agent));
+        let placed = tree.add_child(ROOT, Step::Place(5));
+        let first = tree.add_child(placed, Step::Give(1));
+        let second = tree.add_child(placed, Step::Give(3));
+        tree.add_child(ROOT, Step::Place(6));
+        let winning = tree.add_child(ROOT, Step::Place(3));
+
+        let play = |position, next_piece| Move {
+            position,
+            piece: 8,
+            next_piece,
+        };
+        assert_eq!(
+            tree.explored_moves(ROOT),
+            vec![
+                (play(5, 1), first),
+                (play(5, 3), second),
+                (play(3, 0), winning)
+            ]
+        );
+        assert!(tree.explored_moves(placed).is_empty());
+    }
+
+    #[test]
+    fn find_returns_the_node_holding_the_game_within_the_depth() {
+        let mut tree = Tree::new(setup());
+        let child = tree.add_child(ROOT, Step::Place(0));
+        let grand_child = tree.add_child(child, Step::Give(1));
+        let game = tree[grand_child].game_state.clone();
+
+        assert_eq!(tree.find(&setup(), 0), Some(ROOT));
+        assert_eq!(tree.find(&game, 2), Some(grand_child));
+        assert_eq!(tree.find(&game, 1), None);
+    }
+
+    #[test]
+    fn subtree_keeps_the_branch_below_the_node() {
+        let mut tree = Tree::new(setup());
+        let child = tree.add_child(ROOT, Step::Place(0));
+        let sibling = tree.add_child(ROOT, Step::Place(2));
+        let grand_child = tree.add_child(child, Step::Give(1));
+        tree.add_child(sibling, Step::Give(3));
+        tree.propagate_wins(&[ROOT, child, grand_child], Some(Player::Agent));
+
+        let subtree = tree.subtree(child);
+        assert_eq!(subtree.size(), 2);
+        assert_eq!(subtree[ROOT].game_state, tree[child].game_state);
+        assert_eq!(subtree[ROOT].parent, None);
+        assert_eq!(subtree[ROOT].num_rollouts, 1);
+
+        let new_grand_child = subtree[ROOT].children[0];
+        assert_eq!(subtree[new_grand_child].parent, Some(ROOT));
+        assert_eq!(subtree[new_grand_child].step, Some(Step::Give(1)));
+        assert_eq!(subtree[new_grand_child].wins(Player::Agent), 1);
     }
 
     #[test]
     fn new_returns_an_initialized_node() {
-        let node = NodeBuilder::new(setup()).build();
-        assert_eq!(node.borrow().num_rollouts, 0);
-        assert!(node.borrow().children.is_empty());
+        let tree = Tree::new(setup());
+        assert_eq!(tree[ROOT].num_rollouts, 0);
+        assert!(tree[ROOT].children.is_empty());
+        assert!(tree[ROOT].unvisited_steps.is_none());
+    }
+
+    #[test]
+    fn random_unvisited_step_generates_the_unvisited_steps_once() {
+        let mut tree = Tree::new(setup());
+        let mut rng = StdRng::seed_from_u64(0);
+        let first_step = tree[ROOT].random_unvisited_step(&mut rng);
+        let second_step = tree[ROOT].random_unvisited_step(&mut rng);
+        assert_ne!(first_step, second_step);
+        assert_eq!(tree[ROOT].unvisited_steps.as_ref().unwrap().len(), 16 - 2);
     }
 
     #[test]
     fn record_win_increments_the_wins_for_player() {
-        let root = NodeBuilder::new(setup()).build();
-        root.borrow_mut().record_win(Some(OPPONENT));
-        root.borrow_mut().record_win(Some(OPPONENT));
-        root.borrow_mut().record_win(Some(AGENT));
-        assert_eq!(root.borrow().win_counts.get(OPPONENT).unwrap(), &2);
-        assert_eq!(root.borrow().win_counts.get(AGENT).unwrap(), &1);
+        let mut tree = Tree::new(setup());
+        tree[ROOT].record_win(Some(Player::Opponent));
+        tree[ROOT].record_win(Some(Player::Opponent));
+        tree[ROOT].record_win(Some(Player::Agent));
+        assert_eq!(tree[ROOT].wins(Player::Opponent), 2);
+        assert_eq!(tree[ROOT].wins(Player::Agent), 1);
     }
 
     #[test]
     fn record_win_increments_the_number_of_rollouts() {
-        let root = NodeBuilder::new(setup()).build();
-        root.borrow_mut().record_win(Some(OPPONENT));
-        root.borrow_mut().record_win(Some(OPPONENT));
-        root.borrow_mut().record_win(Some(AGENT));
-        assert_eq!(root.borrow().num_rollouts, 3);
+        let mut tree = Tree::new(setup());
+        tree[ROOT].record_win(Some(Player::Opponent));
+        tree[ROOT].record_win(Some(Player::Opponent));
+        tree[ROOT].record_win(Some(Player::Agent));
+        assert_eq!(tree[ROOT].num_rollouts, 3);
     }
 
     #[test]
     fn record_win_increments_the_number_of_rollouts_with_no_winner() {
-        let root = NodeBuilder::new(setup()).build();
-        root.borrow_mut().record_win(None);
-        root.borrow_mut().record_win(None);
-        root.borrow_mut().record_win(None);
-        assert_eq!(root.borrow().num_rollouts, 3);
-        assert_eq!(root.borrow().win_counts.get(OPPONENT).unwrap(), &0);
-        assert_eq!(root.borrow().win_counts.get(AGENT).unwrap(), &0);
+        let mut tree = Tree::new(setup());
+        tree[ROOT].record_win(None);
+        tree[ROOT].record_win(None);
+        tree[ROOT].record_win(None);
+        assert_eq!(tree[ROOT].num_rollouts, 3);
+        assert_eq!(tree[ROOT].wins(Player::Opponent), 0);
+        assert_eq!(tree[ROOT].wins(Player::Agent), 0);
     }
 
     #[test]
     fn can_add_child_returns_true_with_unvisited_moves() {
-        let root = NodeBuilder::new(setup()).build();
-        assert!(root.borrow().can_add_child());
+        let tree = Tree::new(setup());
+        assert!(tree[ROOT].can_add_child());
     }
 
     #[test]
     fn can_add_child_returns_false_with_no_unvisited_moves() {
-        let root = NodeBuilder::new(setup()).build();
-        root.borrow_mut().unvisited_moves = Vec::new();
-        assert_eq!(root.borrow().can_add_child(), false);
+        let mut tree = Tree::new(setup());
+        tree[ROOT].unvisited_steps = Some(Vec::new());
+        assert!(!tree[ROOT].can_add_child());
+    }
+
+    #[test]
+    fn can_add_child_returns_false_when_game_is_over() {
+        let tree = Tree::new(setup_finished_game());
+        assert!(!tree[ROOT].can_add_child());
     }
 
     #[test]
     fn is_terminal_returns_true_when_game_is_over() {
-        let root = NodeBuilder::new(setup_finished_game()).build();
-        assert!(root.borrow().is_terminal());
+        let tree = Tree::new(setup_finished_game());
+        assert!(tree[ROOT].is_terminal());
     }
 
     #[test]
     fn is_terminal_returns_false_when_game_is_not_over() {
-        let root = NodeBuilder::new(setup()).build();
-        assert_eq!(root.borrow().is_terminal(), false);
+        let tree = Tree::new(setup());
+        assert!(!tree[ROOT].is_terminal());
     }
 
     #[test]
     fn winning_fraction_returns_win_percentage_for_given_player() {
-        let root = NodeBuilder::new(setup()).build();
-        root.borrow_mut().win_counts.insert(AGENT, 28);
-        root.borrow_mut().win_counts.insert(OPPONENT, 22);
-        root.borrow_mut().num_rollouts = 50;
-        assert_eq!(root.borrow().winning_fraction(OPPONENT), 0.44);
-        assert_eq!(root.borrow().winning_fraction(AGENT), 0.56);
+        let mut tree = Tree::new(setup());
+        tree[ROOT].win_counts[Player::Agent.index()] = 28;
+        tree[ROOT].win_counts[Player::Opponent.index()] = 22;
+        tree[ROOT].num_rollouts = 50;
+        assert_eq!(tree[ROOT].winning_fraction(Player::Opponent), 0.44);
+        assert_eq!(tree[ROOT].winning_fraction(Player::Agent), 0.56);
+    }
+
+    #[test]
+    fn mean_reward_counts_draws_as_part_of_a_win() {
+        let mut tree = Tree::new(setup());
+        tree[ROOT].win_counts[Player::Agent.index()] = 20;
+        tree[ROOT].win_counts[Player::Opponent.index()] = 10;
+        tree[ROOT].num_rollouts = 50;
+        assert_eq!(tree[ROOT].num_draws(), 20);
+        assert_eq!(tree[ROOT].mean_reward(Player::Agent, 0.5), 0.6);
+        assert_eq!(tree[ROOT].mean_reward(Player::Opponent, 0.5), 0.4);
+        assert_eq!(tree[ROOT].mean_reward(Player::Agent, 0.0), 0.4);
     }
 }
@@ -0,0 +1,100 @@
+use super::{NodeId, Tree};
+use crate::game::{Player, Step};
+
+// How much a child's all-moves-as-first (AMAF) value counts against its own
+// record. AMAF values come in quickly but are biased, so the weight falls as
+// the child gets visits of its own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)] // No preset turns RAVE on yet.
+pub enum RaveSchedule {
+    // Both values count the same after this many visits: sqrt(k / (3n + k)).
+    Equivalence(f64),
+    // The weight that minimises the error of the blend, for AMAF values off by
+    // about this much: ñ / (n + ñ + 4nñb²).
+    MinimumError(f64),
+}
+
+impl RaveSchedule {
+    // The weight of the AMAF value, from 0 to 1.
+    pub fn weight(self, visits: f64, amaf_visits: f64) -> f64 {
+        match self {
+            RaveSchedule::Equivalence(equivalence) => {
+                (equivalence / (3.0 * visits + equivalence)).sqrt()
+            }
+            RaveSchedule::MinimumError(bias) => {
+                amaf_visits / (visits + amaf_visits + 4.0 * visits * amaf_visits * bias * bias)
+            }
+        }
+    }
+}
+
+// Credit the winner to every child of a node on the path whose step the player
+// at that node took at any point later in the game, in the tree or in the
+// rollout, as if it had been taken first. `steps` are the steps the game took
+// from the root, with who took them. Placements match on the square alone: each
+// piece is placed once a game, so the piece would never match again.
+pub fn propagate_amaf(
+    tree: &mut Tree,
+    path: &[NodeId],
+    steps: &[(Player, Step)],
+    winner: Option<Player>,
+) {
+    for (depth, &node) in path.iter().enumerate() {
+        let player = tree[node].game_state.current_player;
+        let children = tree[node].children.clone();
+        for child in children {
+            let step = tree.step_between(node, child);
+            if steps[depth..].contains(&(player, step)) {
+                tree[child].record_amaf(winner);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{new_board, GameState};
+    use crate::mcts::ROOT;
+
+    #[test]
+    fn equivalence_gives_both_values_the_same_weight_at_k_visits() {
+        let schedule = RaveSchedule::Equivalence(300.0);
+        assert_eq!(schedule.weight(0.0, 10.0), 1.0);
+        assert!((schedule.weight(300.0, 10.0) - 0.5).abs() < 1e-9);
+        assert!(schedule.weight(3000.0, 10.0) < schedule.weight(300.0, 10.0));
+    }
+
+    #[test]
+    fn minimum_error_trusts_amaf_less_as_the_child_gets_visits() {
+        let schedule = RaveSchedule::MinimumError(0.1);
+        assert_eq!(schedule.weight(0.0, 10.0), 1.0);
+        assert!(schedule.weight(10.0, 100.0) > schedule.weight(100.0, 100.0));
+        assert_eq!(schedule.weight(10.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn propagate_amaf_credits_the_steps_the_player_took_later() {
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let mut tree = Tree::new(game);
+        let taken = tree.add_child(ROOT, Step::Place(0));
+        let played_later = tree.add_child(ROOT, Step::Place(5));
+        let played_by_opponent = tree.add_child(ROOT, Step::Place(6));
+        let never_played = tree.add_child(ROOT, Step::Place(7));
+
+        let steps = [
+            (Player::Agent, Step::Place(0)),
+            (Player::Agent, Step::Give(1)),
+            (Player::Opponent, Step::Place(6)),
+            (Player::Opponent, Step::Give(2)),
+            (Player::Agent, Step::Place(5)),
+        ];
+        propagate_amaf(&mut tree, &[ROOT, taken], &steps, Some(Player::Agent));
+
+        assert_eq!(tree[taken].amaf_rollouts, 1);
+        assert_eq!(tree[played_later].amaf_rollouts, 1);
+        assert_eq!(tree[played_later].amaf_wins(Player::Agent), 1);
+        assert_eq!(tree[played_by_opponent].amaf_rollouts, 0);
+        assert_eq!(tree[never_played].amaf_rollouts, 0);
+    }
+}
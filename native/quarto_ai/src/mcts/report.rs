@@ -0,0 +1,370 @@
+use super::{NodeId, Tree, ROOT};
+use crate::game::Move;
+use crate::solver::Outcome;
+use std::cmp::Reverse;
+use std::time::{Duration, Instant};
+
+// What a search decided and how much work went into it.
+#[derive(Debug)]
+pub struct SearchReport {
+    pub selected_move: Move,
+    // Known when the game was solved, or the search proved it.
+    pub outcome: Option<Outcome>,
+    pub rounds: u32,
+    pub elapsed: Duration,
+    // Searching again with this seed repeats the search.
+    pub seed: u64,
+    // Every move the search tried from the root, most visited first.
+    pub children: Vec<ChildReport>,
+    // The line of play the search expects: the selected move, then down the
+    // most visited moves.
+    pub principal_variation: Vec<Move>,
+    pub tree_size: usize,
+}
+
+// How a move from the root fared, from the point of view of the player making it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChildReport {
+    pub node_move: Move,
+    pub visits: i32,
+    pub win: f64,
+    pub draw: f64,
+    pub loss: f64,
+    pub outcome: Option<Outcome>,
+}
+
+impl SearchReport {
+    // A search that sampled the tree.
+    pub(super) fn sampled(tree: &Tree, selected_move: Move, outcome: Option<Outcome>) -> Self {
+        Self {
+            principal_variation: expected_line(tree, &selected_move),
+            selected_move,
+            outcome,
+            rounds: 0,
+            elapsed: Duration::ZERO,
+            seed: 0,
+            children: child_reports(tree),
+            tree_size: tree.size(),
+        }
+    }
+
+    // A game decided without sampling, by a winning move or the solver.
+    pub(super) fn decided(selected_move: Move, outcome: Outcome) -> Self {
+        Self {
+            principal_variation: vec![selected_move.clone()],
+            selected_move,
+            outcome: Some(outcome),
+            rounds: 0,
+            elapsed: Duration::ZERO,
+            seed: 0,
+            children: Vec::new(),
+            tree_size: 0,
+        }
+    }
+
+    pub(super) fn finished(mut self, rounds: u32, started_at: Instant, seed: u64) -> Self {
+        self.rounds = rounds;
+        self.elapsed = started_at.elapsed();
+        self.seed = seed;
+        self
+    }
+
+    // The k most promising moves: proven wins first and proven losses last,
+    // the rest by how much the search looked at them.
+    pub fn candidates(&self, k: usize) -> Vec<ChildReport> {
+        if self.children.is_empty() {
+            let decided = ChildReport::decided(self.selected_move.clone(), self.outcome);
+            return [decided].into_iter().take(k).collect();
+        }
+
+        let mut candidates = self.children.clone();
+        candidates.sort_by_key(|child| {
+            let rank = match child.outcome {
+                Some(Outcome::Win) => 0,
+                Some(Outcome::Loss) => 2,
+                _ => 1,
+            };
+            (rank, Reverse(child.visits))
+        });
+        candidates.truncate(k);
+        candidates
+    }
+
+    pub fn selected_child(&self) -> Option<&ChildReport> {
+        self.children
+            .iter()
+            .find(|child| child.node_move == self.selected_move)
+    }
+}
+
+impl ChildReport {
+    // A move that was never sampled, known only by its outcome.
+    fn decided(node_move: Move, outcome: Option<Outcome>) -> Self {
+        let certainty = |expected| if outcome == Some(expected) { 1.0 } else { 0.0 };
+        Self {
+            node_move,
+            visits: 0,
+            win: certainty(Outcome::Win),
+            draw: certainty(Outcome::Draw),
+            loss: certainty(Outcome::Loss),
+            outcome,
+        }
+    }
+}
+
+fn child_reports(tree: &Tree) -> Vec<ChildReport> {
+    let player = tree[ROOT].game_state.current_player;
+    let mut children: Vec<ChildReport> = tree
+        .explored_moves(ROOT)
+        .into_iter()
+        .map(|(node_move, child)| {
+            let node = &tree[child];
+            let visits = node.num_rollouts.max(1) as f64;
+            let decided: i32 = node.win_counts.iter().sum();
+            let wins = node.wins(player);
+            ChildReport {
+                node_move,
+                visits: node.num_rollouts,
+                win: wins as f64 / visits,
+                draw: (node.num_rollouts - decided) as f64 / visits,
+                loss: (decided - wins) as f64 / visits,
+                outcome: node.proven.map(|proven| proven.outcome_for(player)),
+            }
+        })
+        .collect();
+    children.sort_by_key(|child| Reverse(child.visits));
+    children
+}
+
+pub(super) fn expected_line(tree: &Tree, selected_move: &Move) -> Vec<Move> {
+    let mut line = vec![selected_move.clone()];
+    if let Some(child) = tree.child_for_move(ROOT, selected_move) {
+        line.extend(principal_variation(tree, child));
+    }
+    line
+}
+
+// Follow the most visited square, then the most visited piece handed over
+// from there, down from node until the tree runs out of whole moves.
+fn principal_variation(tree: &Tree, node: NodeId) -> Vec<Move> {
+    let mut moves = Vec::new();
+    let mut node = node;
+    while let Some(placed) = most_visited_child(tree, node) {
+        let next = most_visited_child(tree, placed).unwrap_or(placed);
+        let Some((next_move, _)) = tree
+            .explored_moves(node)
+            .into_iter()
+            .find(|&(_, child)| child == next)
+        else {
+            break;
+        };
+        moves.push(next_move);
+        node = next;
+    }
+    moves
+}
+
+fn most_visited_child(tree: &Tree, node: NodeId) -> Option<NodeId> {
+    tree[node]
+        .children
+        .iter()
+        .copied()
+        .max_by_key(|&child| tree[child].num_rollouts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::Player;
+    use crate::game::{new_board, GameState, Step};
+    use crate::mcts::AgentBuilder;
+
+    fn test_move(position: i32) -> Move {
+        Move {
+            position,
+            piece: 0,
+            next_piece: 1,
+        }
+    }
+
+    fn setup() -> Tree {
+        Tree::new(GameState::new(new_board(), 0, Player::Agent))
+    }
+
+    // Add both steps of a move below node, and return the way down from it.
+    fn add_move(tree: &mut Tree, node: NodeId, node_move: &Move) -> Vec<NodeId> {
+        let placed = tree.add_child(node, Step::Place(node_move.position));
+        let given = tree.add_child(placed, Step::Give(node_move.next_piece));
+        vec![node, placed, given]
+    }
+
+    #[test]
+    fn children_split_the_visits_into_wins_draws_and_losses() {
+        let mut tree = setup();
+        let path = add_move(&mut tree, ROOT, &test_move(0));
+        for winner in [
+            Some(Player::Agent),
+            Some(Player::Agent),
+            Some(Player::Opponent),
+            None,
+        ] {
+            tree.propagate_wins(&path, winner);
+        }
+
+        let children = child_reports(&tree);
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].node_move, test_move(0));
+        assert_eq!(children[0].visits, 4);
+        assert_eq!(children[0].win, 0.5);
+        assert_eq!(children[0].draw, 0.25);
+        assert_eq!(children[0].loss, 0.25);
+    }
+
+    #[test]
+    fn children_are_ordered_by_visits() {
+        let mut tree = setup();
+        let quiet = add_move(&mut tree, ROOT, &test_move(0));
+        let busy = add_move(&mut tree, ROOT, &test_move(1));
+        tree.propagate_wins(&quiet, None);
+        tree.propagate_wins(&busy, None);
+        tree.propagate_wins(&busy, None);
+
+        let positions: Vec<i32> = child_reports(&tree)
+            .iter()
+            .map(|child| child.node_move.position)
+            .collect();
+        assert_eq!(positions, vec![1, 0]);
+    }
+
+    fn child(position: i32, visits: i32, outcome: Option<Outcome>) -> ChildReport {
+        ChildReport {
+            node_move: test_move(position),
+            visits,
+            win: 0.0,
+            draw: 0.0,
+            loss: 0.0,
+            outcome,
+        }
+    }
+
+    #[test]
+    fn candidates_rank_proven_wins_first_and_proven_losses_last() {
+        let mut report = SearchReport::decided(test_move(0), Outcome::Win);
+        report.children = vec![
+            child(0, 50, Some(Outcome::Loss)),
+            child(1, 30, None),
+            child(2, 40, Some(Outcome::Draw)),
+            child(3, 5, Some(Outcome::Win)),
+        ];
+
+        let positions: Vec<i32> = report
+            .candidates(3)
+            .iter()
+            .map(|candidate| candidate.node_move.position)
+            .collect();
+        assert_eq!(positions, vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn candidates_fall_back_to_the_decided_move() {
+        let report = SearchReport::decided(test_move(3), Outcome::Draw);
+        let candidates = report.candidates(5);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].node_move, test_move(3));
+        assert_eq!(candidates[0].draw, 1.0);
+        assert!(report.candidates(0).is_empty());
+    }
+
+    #[test]
+    fn search_suggests_moves_for_the_opponent_too() {
+        let agent = AgentBuilder::new(1.5).num_rounds(600).seed(1).build();
+        let game = GameState::new(new_board(), 0, Player::Opponent);
+        let candidates = agent.search(game.clone()).candidates(3);
+
+        assert_eq!(candidates.len(), 3);
+        for candidate in &candidates {
+            assert!(game.legal_moves().contains(&candidate.node_move));
+        }
+        assert!(candidates[0].visits >= candidates[2].visits);
+    }
+
+    #[test]
+    fn expected_line_follows_the_most_visited_replies() {
+        let mut tree = setup();
+        let path = add_move(&mut tree, ROOT, &test_move(0));
+        let reply = Move {
+            position: 5,
+            piece: 1,
+            next_piece: 2,
+        };
+        let other_reply = Move {
+            position: 6,
+            piece: 1,
+            next_piece: 2,
+        };
+        let reply_path = [&path[..2], &add_move(&mut tree, path[2], &reply)].concat();
+        let other_path = [&path[..2], &add_move(&mut tree, path[2], &other_reply)].concat();
+        tree.propagate_wins(&reply_path, None);
+        tree.propagate_wins(&reply_path, None);
+        tree.propagate_wins(&other_path, None);
+
+        assert_eq!(
+            expected_line(&tree, &test_move(0)),
+            vec![test_move(0), reply]
+        );
+        assert_eq!(expected_line(&tree, &test_move(3)), vec![test_move(3)]);
+    }
+
+    #[test]
+    fn search_reports_every_round_and_the_line_it_expects() {
+        let agent = AgentBuilder::new(1.5).num_rounds(3000).seed(1).build();
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let mut tree = Tree::new(game.clone());
+        let report = agent.search_tree(&mut tree);
+
+        // Rounds that stopped after placing the piece count towards no whole move.
+        let visits: i32 = report.children.iter().map(|child| child.visits).sum();
+        assert_eq!(tree[ROOT].num_rollouts, 3000);
+        assert!(visits <= 3000 && visits > 2500, "{} visits", visits);
+        assert_eq!(report.tree_size, tree.size());
+        assert_eq!(report.principal_variation[0], report.selected_move);
+        assert!(report.principal_variation.len() > 1);
+        assert!(report.selected_child().is_some());
+
+        let mut line_game = game;
+        for line_move in &report.principal_variation {
+            assert!(line_game.legal_moves().contains(line_move));
+            line_game = line_game.apply_move(line_move);
+        }
+    }
+
+    #[test]
+    fn search_reports_the_whole_line_across_threads() {
+        let agent = AgentBuilder::new(1.5)
+            .num_rounds(3000)
+            .num_threads(2)
+            .seed(1)
+            .build();
+        let report = agent.search(GameState::new(new_board(), 0, Player::Agent));
+
+        assert_eq!(report.principal_variation[0], report.selected_move);
+        assert!(report.principal_variation.len() > 1);
+        assert!(report.tree_size > 3000);
+    }
+
+    #[test]
+    fn a_move_decided_without_search_reports_every_threads_tree() {
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[1] = Some(2);
+        board[2] = Some(4);
+        let game = GameState::new(board, 8, Player::Agent);
+        let agent = AgentBuilder::new(1.5).num_threads(2).build();
+        let mut trees = vec![Tree::new(game.clone()), Tree::new(game)];
+        let report = agent.search_trees(&mut trees);
+
+        assert_eq!(report.rounds, 0);
+        assert_eq!(report.selected_move.position, 3);
+        assert_eq!(report.tree_size, 2);
+    }
+}
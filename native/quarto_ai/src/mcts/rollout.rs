@@ -0,0 +1,132 @@
+use crate::game::{GameState, Piece, Position};
+use rand::rngs::StdRng;
+use rand::Rng;
+
+// How a rollout takes each step on its way to the end of the game. Policies are
+// shared by every search thread, so they can't keep state of their own.
+pub trait RolloutPolicy: Send + Sync {
+    // Where to put the active piece.
+    fn select_position(&self, game: &GameState, rng: &mut StdRng) -> Position;
+
+    // Which piece to hand over, once the active piece is on the board.
+    fn select_piece(&self, game: &GameState, rng: &mut StdRng) -> Piece;
+}
+
+// Any empty square and any remaining piece, all equally likely.
+pub struct UniformRollout;
+
+// Win on the spot when the active piece allows it, otherwise move at random.
+pub struct WinningRollout;
+
+// Win on the spot when possible, otherwise place the piece at random and hand
+// over a piece the other player can't win with straight away, if there is one.
+pub struct SafeRollout;
+
+impl RolloutPolicy for UniformRollout {
+    fn select_position(&self, game: &GameState, rng: &mut StdRng) -> Position {
+        random_position(game, rng)
+    }
+
+    fn select_piece(&self, game: &GameState, rng: &mut StdRng) -> Piece {
+        random_piece(game, rng)
+    }
+}
+
+impl RolloutPolicy for WinningRollout {
+    fn select_position(&self, game: &GameState, rng: &mut StdRng) -> Position {
+        winning_position(game).unwrap_or_else(|| random_position(game, rng))
+    }
+
+    fn select_piece(&self, game: &GameState, rng: &mut StdRng) -> Piece {
+        random_piece(game, rng)
+    }
+}
+
+impl RolloutPolicy for SafeRollout {
+    fn select_position(&self, game: &GameState, rng: &mut StdRng) -> Position {
+        winning_position(game).unwrap_or_else(|| random_position(game, rng))
+    }
+
+    fn select_piece(&self, game: &GameState, rng: &mut StdRng) -> Piece {
+        let safe_pieces: Vec<Piece> = game
+            .pieces_to_give()
+            .filter(|&piece| game.give(piece).winning_move().is_none())
+            .collect();
+        if safe_pieces.is_empty() {
+            random_piece(game, rng)
+        } else {
+            safe_pieces[rng.gen_range(0..safe_pieces.len())]
+        }
+    }
+}
+
+fn winning_position(game: &GameState) -> Option<Position> {
+    game.winning_move()
+        .map(|winning_move| winning_move.position)
+}
+
+fn random_position(game: &GameState, rng: &mut StdRng) -> Position {
+    let positions: Vec<Position> = game.empty_positions().collect();
+    positions[rng.gen_range(0..positions.len())]
+}
+
+fn random_piece(game: &GameState, rng: &mut StdRng) -> Piece {
+    let pieces: Vec<Piece> = game.pieces_to_give().collect();
+    pieces[rng.gen_range(0..pieces.len())]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{new_board, Player};
+    use rand::SeedableRng;
+
+    // Pieces 0, 2 and 4 share two unset bits, so most pieces win on square 3.
+    fn game_with_a_win() -> GameState {
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[1] = Some(2);
+        board[2] = Some(4);
+        GameState::new(board, 8, Player::Agent)
+    }
+
+    #[test]
+    fn winning_rollouts_take_a_win_when_there_is_one() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let game = game_with_a_win();
+        for _ in 0..20 {
+            assert_eq!(WinningRollout.select_position(&game, &mut rng), 3);
+        }
+    }
+
+    #[test]
+    fn uniform_rollouts_pass_up_some_wins() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let game = game_with_a_win();
+        let missed = (0..20)
+            .filter(|_| UniformRollout.select_position(&game, &mut rng) != 3)
+            .count();
+        assert!(missed > 0);
+    }
+
+    #[test]
+    fn safe_rollouts_never_hand_over_a_winning_piece_when_they_can_help_it() {
+        // With 8 placed out of the way, only 9, 11, 13 and 15 can't finish the
+        // top row.
+        let game = game_with_a_win().place(15);
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..20 {
+            let piece = SafeRollout.select_piece(&game, &mut rng);
+            assert!([9, 11, 13, 15].contains(&piece), "handed over {}", piece);
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let unsafe_pieces = (0..20)
+            .filter(|_| {
+                let piece = UniformRollout.select_piece(&game, &mut rng);
+                game.give(piece).winning_move().is_some()
+            })
+            .count();
+        assert!(unsafe_pieces > 0);
+    }
+}
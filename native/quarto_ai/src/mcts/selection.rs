@@ -0,0 +1,258 @@
+use crate::game::{GameState, Step};
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::f64::consts::PI;
+
+// What a selection policy gets to know about a child when picking which one
+// to search next. Rewards are for the player choosing between the children.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChildStats {
+    pub visits: f64,
+    // The visits of the child and all its siblings.
+    pub parent_visits: f64,
+    // The mean reward, blended with the all-moves-as-first reward when RAVE is on.
+    pub reward: f64,
+    // How far the rewards of the child's own rollouts spread around their mean.
+    pub reward_variance: f64,
+    // Set when the child was added, by the policy's own prior.
+    pub prior: f64,
+}
+
+// How the search picks which child to go down next. Policies are shared by
+// every search thread, so they can't keep state of their own. Exploration is
+// the agent's temperature: higher is volatile, lower is focused.
+pub trait SelectionPolicy: Send + Sync {
+    // Higher is more worth searching.
+    fn score(&self, child: &ChildStats, exploration: f64, rng: &mut StdRng) -> f64;
+
+    // How likely step looks from game before searching it, from 0 to 1.
+    // Only policies that look at priors need to say.
+    fn prior(&self, _game: &GameState, _step: Step) -> f64 {
+        1.0
+    }
+}
+
+// Upper confidence bound: the mean reward plus a bonus that shrinks as the
+// child gets visits. Children that were never visited come first.
+pub struct Ucb1;
+
+// UCB1 with the bonus cut down for children whose rewards hardly vary.
+#[allow(dead_code)] // Not picked by any preset yet.
+pub struct Ucb1Tuned;
+
+// Predictor + UCB, as in AlphaZero: the bonus is spread over the children by
+// their prior, so likely steps get searched first.
+#[allow(dead_code)] // Not picked by any preset yet.
+pub struct Puct {
+    // Weighs a step against its siblings; only the ratios matter.
+    pub prior: fn(&GameState, Step) -> f64,
+}
+
+// Pick the child whose reward drawn from its beta posterior is highest, so
+// children are searched as often as they are likely to be the best.
+#[allow(dead_code)] // Not picked by any preset yet.
+pub struct ThompsonSampling;
+
+impl SelectionPolicy for Ucb1 {
+    fn score(&self, child: &ChildStats, exploration: f64, _rng: &mut StdRng) -> f64 {
+        if child.visits == 0.0 {
+            return f64::INFINITY;
+        }
+        child.reward + exploration * (child.parent_visits.ln() / child.visits).sqrt()
+    }
+}
+
+impl SelectionPolicy for Ucb1Tuned {
+    fn score(&self, child: &ChildStats, exploration: f64, _rng: &mut StdRng) -> f64 {
+        if child.visits == 0.0 {
+            return f64::INFINITY;
+        }
+        let log_ratio = child.parent_visits.ln() / child.visits;
+        // The variance bound never goes over 1/4, the most a reward in [0, 1] can vary.
+        let variance_bound = (child.reward_variance + (2.0 * log_ratio).sqrt()).min(0.25);
+        child.reward + exploration * (log_ratio * variance_bound).sqrt()
+    }
+}
+
+impl Default for Puct {
+    fn default() -> Self {
+        Self {
+            prior: tactical_prior,
+        }
+    }
+}
+
+impl SelectionPolicy for Puct {
+    fn score(&self, child: &ChildStats, exploration: f64, _rng: &mut StdRng) -> f64 {
+        let bonus = exploration * child.prior * child.parent_visits.sqrt() / (1.0 + child.visits);
+        child.reward + bonus
+    }
+
+    fn prior(&self, game: &GameState, step: Step) -> f64 {
+        let total: f64 = game
+            .legal_steps()
+            .into_iter()
+            .map(|legal_step| (self.prior)(game, legal_step))
+            .sum();
+        if total > 0.0 {
+            (self.prior)(game, step) / total
+        } else {
+            0.0
+        }
+    }
+}
+
+impl SelectionPolicy for ThompsonSampling {
+    fn score(&self, child: &ChildStats, _exploration: f64, rng: &mut StdRng) -> f64 {
+        // Draws count as part win, part loss.
+        let wins = child.reward * child.visits;
+        let losses = child.visits - wins;
+        sample_beta(1.0 + wins, 1.0 + losses, rng)
+    }
+}
+
+// Every step alike.
+#[allow(dead_code)] // Only for Puct, which no preset picks yet.
+pub fn uniform_prior(_game: &GameState, _step: Step) -> f64 {
+    1.0
+}
+
+// Favour squares that win on the spot, and shy away from pieces the other
+// player can win with straight away.
+#[allow(dead_code)] // Only for Puct, which no preset picks yet.
+pub fn tactical_prior(game: &GameState, step: Step) -> f64 {
+    match step {
+        Step::Place(position) if game.place(position).winner().is_some() => 8.0,
+        Step::Give(piece) if game.give(piece).winning_move().is_some() => 0.125,
+        _ => 1.0,
+    }
+}
+
+#[allow(dead_code)] // Only Thompson sampling draws from these.
+fn sample_beta(alpha: f64, beta: f64, rng: &mut StdRng) -> f64 {
+    let x = sample_gamma(alpha, rng);
+    let y = sample_gamma(beta, rng);
+    x / (x + y)
+}
+
+// Marsaglia and Tsang's method, which needs a shape of at least 1.
+#[allow(dead_code)]
+fn sample_gamma(shape: f64, rng: &mut StdRng) -> f64 {
+    let d = shape - 1.0 / 3.0;
+    let c = 1.0 / (9.0 * d).sqrt();
+    loop {
+        let x = sample_normal(rng);
+        let v = (1.0 + c * x).powi(3);
+        if v <= 0.0 {
+            continue;
+        }
+        let u: f64 = rng.gen();
+        if u.ln() < 0.5 * x * x + d - d * v + d * v.ln() {
+            return d * v;
+        }
+    }
+}
+
+// Box-Muller. 1 - u keeps the logarithm away from zero.
+#[allow(dead_code)]
+fn sample_normal(rng: &mut StdRng) -> f64 {
+    let u: f64 = 1.0 - rng.gen::<f64>();
+    let angle: f64 = 2.0 * PI * rng.gen::<f64>();
+    (-2.0 * u.ln()).sqrt() * angle.cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{new_board, Player};
+    use rand::SeedableRng;
+
+    fn stats(visits: f64, reward: f64, reward_variance: f64) -> ChildStats {
+        ChildStats {
+            visits,
+            parent_visits: 100.0,
+            reward,
+            reward_variance,
+            prior: 0.5,
+        }
+    }
+
+    #[test]
+    fn ucb1_uses_the_natural_logarithm_and_tries_unvisited_children_first() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let expected = 0.5 + (100f64.ln() / 10.0).sqrt();
+        let score = Ucb1.score(&stats(10.0, 0.5, 0.0), 1.0, &mut rng);
+        assert!((score - expected).abs() < 1e-9);
+        assert_eq!(
+            Ucb1.score(&stats(0.0, 0.0, 0.0), 1.0, &mut rng),
+            f64::INFINITY
+        );
+        assert_eq!(
+            Ucb1Tuned.score(&stats(0.0, 0.0, 0.0), 1.0, &mut rng),
+            f64::INFINITY
+        );
+    }
+
+    #[test]
+    fn ucb1_tuned_explores_steady_children_less() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let well_visited = |reward_variance| ChildStats {
+            parent_visits: 10000.0,
+            ..stats(5000.0, 0.5, reward_variance)
+        };
+        let steady = Ucb1Tuned.score(&well_visited(0.0), 1.0, &mut rng);
+        let erratic = Ucb1Tuned.score(&well_visited(0.25), 1.0, &mut rng);
+        assert!(steady < erratic);
+        assert!(erratic < Ucb1.score(&well_visited(0.25), 1.0, &mut rng));
+    }
+
+    #[test]
+    fn puct_searches_likely_children_first() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let likely = ChildStats {
+            prior: 0.8,
+            ..stats(10.0, 0.5, 0.0)
+        };
+        let unlikely = ChildStats {
+            prior: 0.1,
+            ..stats(10.0, 0.5, 0.0)
+        };
+        let puct = Puct::default();
+        assert!(puct.score(&likely, 1.0, &mut rng) > puct.score(&unlikely, 1.0, &mut rng));
+    }
+
+    #[test]
+    fn tactical_priors_favour_wins_and_add_up_to_one() {
+        // Pieces 0, 2 and 4 share two unset bits, and so does 8 on square 3.
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[1] = Some(2);
+        board[2] = Some(4);
+        let game = GameState::new(board, 8, Player::Agent);
+        let puct = Puct::default();
+
+        assert!(puct.prior(&game, Step::Place(3)) > puct.prior(&game, Step::Place(4)));
+        let total: f64 = game
+            .legal_steps()
+            .into_iter()
+            .map(|step| puct.prior(&game, step))
+            .sum();
+        assert!((total - 1.0).abs() < 1e-9);
+
+        // Handing over 9 can't complete the top row; 6 can.
+        let placed = game.place(15);
+        assert!(puct.prior(&placed, Step::Give(9)) > puct.prior(&placed, Step::Give(6)));
+    }
+
+    #[test]
+    fn thompson_sampling_draws_around_the_mean_reward() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let draws: Vec<f64> = (0..2000)
+            .map(|_| ThompsonSampling.score(&stats(400.0, 0.75, 0.0), 1.0, &mut rng))
+            .collect();
+        let mean = draws.iter().sum::<f64>() / draws.len() as f64;
+        assert!((mean - 0.75).abs() < 0.01, "mean {}", mean);
+        assert!(draws.iter().all(|&draw| (0.0..=1.0).contains(&draw)));
+        assert!(draws.iter().any(|&draw| draw != draws[0]));
+    }
+}
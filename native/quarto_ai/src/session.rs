@@ -0,0 +1,335 @@
+use crate::game::GameState;
+use crate::mcts::{Agent, SearchReport, Tree, ROOT};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// The tree is kept from the agent's own move, so by the time the agent is asked
+// again the game sits the opponent's reply further along: a piece placed and
+// one handed over, two levels of the tree.
+const MAX_REUSE_DEPTH: usize = 2;
+
+// Keeps the search trees alive between the agent's turns, one for each of the
+// agent's threads. Each search picks up from the branches matching the game
+// it's given, instead of starting from scratch. In between, the trees can keep
+// growing on threads of their own while the opponent thinks.
+#[derive(Default)]
+pub struct SearchSession {
+    trees: Mutex<Vec<Tree>>,
+    pondering: Mutex<Option<Pondering>>,
+}
+
+// A background search, which hands the trees back when it's done.
+struct Pondering {
+    stop: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<Tree>>,
+}
+
+impl SearchSession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn search(&self, agent: &Agent, game: GameState) -> SearchReport {
+        self.stop_pondering();
+        let mut stored_trees = self.trees.lock().unwrap_or_else(|err| err.into_inner());
+        let mut trees: Vec<Tree> = stored_trees
+            .drain(..)
+            .filter_map(|tree| reuse_tree(tree, &game))
+            .collect();
+        trees.resize_with(agent.num_threads(), || agent.new_tree(game.clone()));
+
+        let report = agent.search_trees(&mut trees);
+        *stored_trees = trees
+            .iter()
+            .filter_map(|tree| {
+                tree.child_for_move(ROOT, &report.selected_move)
+                    .map(|child| tree.subtree(child))
+            })
+            .collect();
+        report
+    }
+
+    // Keep searching the stored trees in the background, each within the
+    // agent's budget, until the next search or the session is dropped.
+    pub fn start_pondering(&self, agent: Agent) {
+        self.stop_pondering();
+        let trees: Vec<Tree> = self
+            .trees
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .drain(..)
+            .collect();
+        if trees.is_empty() {
+            return;
+        }
+
+        let agent = Arc::new(agent);
+        let stop = Arc::new(AtomicBool::new(false));
+        let workers = trees
+            .into_iter()
+            .map(|mut tree| {
+                let agent = agent.clone();
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    agent.ponder(&mut tree, &stop);
+                    tree
+                })
+            })
+            .collect();
+        *self.pondering.lock().unwrap_or_else(|err| err.into_inner()) =
+            Some(Pondering { stop, workers });
+    }
+
+    // Stop the background search, if there is one, and forget the stored trees,
+    // so the next search starts from scratch.
+    pub fn clear(&self) {
+        self.stop_pondering();
+        self.trees
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clear();
+    }
+
+    // Stop the background search, if there is one, and store its trees again.
+    // A thread that panicked loses its tree, and the next search starts it over.
+    pub fn stop_pondering(&self) {
+        let pondering = self
+            .pondering
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .take();
+        let Some(pondering) = pondering else {
+            return;
+        };
+
+        pondering.stop.store(true, Ordering::Relaxed);
+        let trees = pondering
+            .workers
+            .into_iter()
+            .filter_map(|worker| worker.join().ok());
+        self.trees
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .extend(trees);
+    }
+}
+
+impl Drop for SearchSession {
+    fn drop(&mut self) {
+        self.stop_pondering();
+    }
+}
+
+fn reuse_tree(tree: Tree, game: &GameState) -> Option<Tree> {
+    let node = tree.find(game, MAX_REUSE_DEPTH)?;
+    Some(tree.subtree(node))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::new_board;
+    use crate::game::Player;
+    use crate::mcts::AgentBuilder;
+    use std::time::{Duration, Instant};
+
+    // The tree of the first thread, or the only one.
+    fn stored_tree(session: &SearchSession) -> Tree {
+        session.trees.lock().unwrap()[0].clone()
+    }
+
+    #[test]
+    fn search_keeps_the_branch_of_the_selected_move() {
+        let session = SearchSession::new();
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let report = session.search(&Agent::new(500, 1.5), game.clone());
+
+        let tree = stored_tree(&session);
+        assert_eq!(
+            tree[ROOT].game_state,
+            game.apply_move(&report.selected_move)
+        );
+        assert!(tree[ROOT].num_rollouts > 0);
+    }
+
+    #[test]
+    fn search_continues_from_the_opponents_reply() {
+        let session = SearchSession::new();
+        let agent = Agent::new(3000, 1.5);
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let agent_move = session.search(&agent, game.clone()).selected_move;
+
+        // Reply with the move the agent explored the most.
+        let stored_tree = stored_tree(&session);
+        let (reply_move, reply) = stored_tree
+            .explored_moves(ROOT)
+            .into_iter()
+            .max_by_key(|&(_, child)| stored_tree[child].num_rollouts)
+            .unwrap();
+        let reply_game = game.apply_move(&agent_move).apply_move(&reply_move);
+
+        let reused_tree = reuse_tree(stored_tree.clone(), &reply_game).unwrap();
+        assert_eq!(reused_tree[ROOT].game_state, reply_game);
+        assert_eq!(
+            reused_tree[ROOT].num_rollouts,
+            stored_tree[reply].num_rollouts
+        );
+
+        let report = session.search(&agent, reply_game.clone());
+        assert_eq!(report.rounds, 3000);
+        assert!(reply_game.legal_moves().contains(&report.selected_move));
+    }
+
+    #[test]
+    fn search_keeps_a_tree_for_every_thread() {
+        let session = SearchSession::new();
+        let agent = AgentBuilder::new(1.5)
+            .num_rounds(3000)
+            .num_threads(2)
+            .seed(4)
+            .build();
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let report = session.search(&agent, game.clone());
+        assert_eq!(report.rounds, 6000);
+
+        let trees = session.trees.lock().unwrap().clone();
+        assert_eq!(trees.len(), 2);
+        let after_move = game.apply_move(&report.selected_move);
+        for tree in &trees {
+            assert_eq!(tree[ROOT].game_state, after_move);
+        }
+
+        // Both threads pick up where they left off after the reply.
+        let (reply_move, _) = trees[0]
+            .explored_moves(ROOT)
+            .into_iter()
+            .max_by_key(|&(_, child)| trees[0][child].num_rollouts)
+            .unwrap();
+        let reply_game = after_move.apply_move(&reply_move);
+        let reused_rollouts: i32 = trees
+            .iter()
+            .filter_map(|tree| reuse_tree(tree.clone(), &reply_game))
+            .map(|tree| tree[ROOT].num_rollouts)
+            .sum();
+        assert!(reused_rollouts > 0);
+        let report = session.search(&agent, reply_game.clone());
+        assert!(reply_game.legal_moves().contains(&report.selected_move));
+    }
+
+    #[test]
+    fn search_keeps_sharing_transposed_nodes_between_turns() {
+        let agent = AgentBuilder::new(1.5)
+            .num_rounds(300)
+            .transposition_table(true)
+            .build();
+        let session = SearchSession::new();
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let report = session.search(&agent, game.clone());
+        assert!(game.legal_moves().contains(&report.selected_move));
+
+        // Replaying a move already in the stored tree finds the existing node.
+        let mut stored_tree = stored_tree(&session);
+        let child = stored_tree[ROOT].children[0];
+        let child_step = stored_tree[child].step.unwrap();
+        let size = stored_tree.size();
+        assert_eq!(stored_tree.add_child(ROOT, child_step), child);
+        assert_eq!(stored_tree.size(), size);
+    }
+
+    fn wait_for_pondering(session: &SearchSession) {
+        while !session
+            .pondering
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_none_or(|pondering| pondering.workers.iter().all(JoinHandle::is_finished))
+        {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn pondering_grows_the_tree_the_next_search_starts_from() {
+        let session = SearchSession::new();
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let agent_move = session
+            .search(&Agent::new(300, 1.5), game.clone())
+            .selected_move;
+        let rollouts_before = stored_tree(&session)[ROOT].num_rollouts;
+
+        session.start_pondering(Agent::new(300, 1.5));
+        assert!(session.trees.lock().unwrap().is_empty());
+        wait_for_pondering(&session);
+        session.stop_pondering();
+
+        let pondered_tree = stored_tree(&session);
+        assert_eq!(pondered_tree[ROOT].num_rollouts, rollouts_before + 300);
+
+        let (reply_move, _) = pondered_tree
+            .explored_moves(ROOT)
+            .into_iter()
+            .max_by_key(|&(_, child)| pondered_tree[child].num_rollouts)
+            .unwrap();
+        let reply_game = game.apply_move(&agent_move).apply_move(&reply_move);
+        let report = session.search(&Agent::new(300, 1.5), reply_game.clone());
+        assert!(reply_game.legal_moves().contains(&report.selected_move));
+    }
+
+    #[test]
+    fn search_stops_pondering_straight_away() {
+        let session = SearchSession::new();
+        let game = GameState::new(new_board(), 0, Player::Agent);
+        let agent_move = session
+            .search(&Agent::new(100, 1.5), game.clone())
+            .selected_move;
+
+        let endless = AgentBuilder::new(1.5)
+            .time_budget(Duration::from_secs(600))
+            .build();
+        session.start_pondering(endless);
+        thread::sleep(Duration::from_millis(20));
+
+        let started_at = Instant::now();
+        let after_move = game.apply_move(&agent_move);
+        let reply = after_move.legal_moves()[0].clone();
+        session.search(&Agent::new(100, 1.5), after_move.apply_move(&reply));
+        assert!(started_at.elapsed() < Duration::from_secs(60));
+        assert!(session.pondering.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn a_cleared_session_searches_like_a_new_one() {
+        let seeded = || AgentBuilder::new(1.5).num_rounds(300).seed(7).build();
+        let game = GameState::new(new_board(), 0, Player::Agent);
+
+        let session = SearchSession::new();
+        session.search(&Agent::new(300, 1.5), game.clone());
+        session.start_pondering(Agent::new(300, 1.5));
+        wait_for_pondering(&session);
+        session.clear();
+        assert!(session.trees.lock().unwrap().is_empty());
+        assert!(session.pondering.lock().unwrap().is_none());
+
+        let replayed = session.search(&seeded(), game.clone());
+        let fresh = SearchSession::new().search(&seeded(), game);
+        assert_eq!(replayed.selected_move, fresh.selected_move);
+        assert_eq!(replayed.tree_size, fresh.tree_size);
+    }
+
+    #[test]
+    fn reuse_tree_starts_over_for_an_unrelated_game() {
+        let session = SearchSession::new();
+        session.search(
+            &Agent::new(50, 1.5),
+            GameState::new(new_board(), 0, Player::Agent),
+        );
+        let stored_tree = stored_tree(&session);
+
+        let mut board = new_board();
+        board[0] = Some(1);
+        board[15] = Some(2);
+        let unrelated_game = GameState::new(board, 3, Player::Agent);
+        assert!(reuse_tree(stored_tree, &unrelated_game).is_none());
+    }
+}
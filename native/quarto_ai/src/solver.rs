@@ -0,0 +1,353 @@
+use crate::game::{GameState, Move};
+use std::cmp::max;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/* Exact endgame solver
+
+ - negamax: a position is worth the best of its moves, where a move is worth
+   minus what the resulting position is worth to the opponent
+ - alpha-beta pruning stops looking at moves once one is good enough
+ - a transposition table remembers positions already solved, which Quarto
+   reaches over and over through different move orders
+ - only feasible for boards that are nearly full, so it can be given a deadline
+   and gives up once that passes
+
+*/
+const WIN: i8 = 1;
+const DRAW: i8 = 0;
+const LOSS: i8 = -1;
+// Looking at the clock on every node would cost more than the node itself.
+const NODES_PER_CLOCK_CHECK: u64 = 1024;
+
+// The result of the game for the player to move, with perfect play on both sides.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Outcome {
+    Win,
+    Draw,
+    Loss,
+}
+
+impl Outcome {
+    fn from_value(value: i8) -> Self {
+        match value {
+            WIN => Outcome::Win,
+            DRAW => Outcome::Draw,
+            _ => Outcome::Loss,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Solution {
+    pub best_move: Move,
+    pub outcome: Outcome,
+}
+
+#[derive(Clone, Copy)]
+enum Bound {
+    Exact,
+    Lower,
+    Upper,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    value: i8,
+    bound: Bound,
+}
+
+#[derive(Default)]
+pub struct Solver {
+    table: HashMap<u128, Entry>,
+    deadline: Option<Instant>,
+    nodes: u64,
+    out_of_time: bool,
+}
+
+impl Solver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A solver that gives up once deadline passes.
+    pub fn with_deadline(deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..Self::default()
+        }
+    }
+
+    #[allow(dead_code)] // Searches call try_solve, deadline or not.
+    pub fn solve(&mut self, game: &GameState) -> Solution {
+        self.try_solve(game)
+            .expect("A solver without a deadline always finishes")
+    }
+
+    // None when the deadline passed before the game was solved.
+    pub fn try_solve(&mut self, game: &GameState) -> Option<Solution> {
+        if let Some(winning_move) = game.winning_move() {
+            return Some(Solution {
+                best_move: winning_move,
+                outcome: Outcome::Win,
+            });
+        }
+
+        let mut best_move = None;
+        let mut best_value = LOSS - 1;
+        for legal_move in game.legal_moves() {
+            let value = self.move_value(game, &legal_move, best_value, WIN);
+            if self.out_of_time {
+                return None;
+            }
+            if value > best_value {
+                best_value = value;
+                best_move = Some(legal_move);
+            }
+            if best_value == WIN {
+                break;
+            }
+        }
+
+        Some(Solution {
+            best_move: best_move.expect("Solved a game with no legal moves"),
+            outcome: Outcome::from_value(best_value),
+        })
+    }
+
+    // Once out of time, every value is made up, and the search just unwinds.
+    fn check_clock(&mut self) -> bool {
+        self.nodes += 1;
+        if let Some(deadline) = self.deadline {
+            if self.nodes.is_multiple_of(NODES_PER_CLOCK_CHECK) && Instant::now() >= deadline {
+                self.out_of_time = true;
+            }
+        }
+        self.out_of_time
+    }
+
+    fn negamax(&mut self, game: &GameState, mut alpha: i8, beta: i8) -> i8 {
+        if self.check_clock() {
+            return DRAW;
+        }
+        if game.winning_move().is_some() {
+            return WIN;
+        }
+
+        let key = game.position_key();
+        if let Some(entry) = self.table.get(&key) {
+            match entry.bound {
+                Bound::Exact => return entry.value,
+                Bound::Lower if entry.value >= beta => return entry.value,
+                Bound::Upper if entry.value <= alpha => return entry.value,
+                Bound::Lower => alpha = max(alpha, entry.value),
+                Bound::Upper => (),
+            }
+        }
+
+        let original_alpha = alpha;
+        let mut best_value = LOSS;
+        for legal_move in game.legal_moves() {
+            let value = self.move_value(game, &legal_move, alpha, beta);
+            if self.out_of_time {
+                return DRAW;
+            }
+            best_value = max(best_value, value);
+            alpha = max(alpha, value);
+            if alpha >= beta {
+                break;
+            }
+        }
+
+        let bound = if best_value <= original_alpha {
+            Bound::Upper
+        } else if best_value >= beta {
+            Bound::Lower
+        } else {
+            Bound::Exact
+        };
+        self.table.insert(
+            key,
+            Entry {
+                value: best_value,
+                bound,
+            },
+        );
+        best_value
+    }
+
+    // Only called once there is no winning placement, so a move that fills
+    // the board can only be a draw.
+    fn move_value(&mut self, game: &GameState, legal_move: &Move, alpha: i8, beta: i8) -> i8 {
+        let next_game = game.apply_move(legal_move);
+        if next_game.is_over() {
+            return DRAW;
+        }
+        -self.negamax(&next_game, -beta, -alpha)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::Player;
+    use crate::game::{new_board, Board};
+
+    fn draw_board() -> Board {
+        [
+            Some(7),
+            Some(8),
+            Some(5),
+            Some(10),
+            Some(12),
+            Some(3),
+            Some(14),
+            Some(1),
+            Some(15),
+            Some(13),
+            Some(9),
+            Some(6),
+            Some(2),
+            Some(11),
+            Some(4),
+            Some(0),
+        ]
+    }
+
+    #[test]
+    fn try_solve_gives_up_once_the_deadline_passes() {
+        let mut board = new_board();
+        board[0] = Some(1);
+        let game = GameState::new(board, 2, Player::Agent);
+        assert_eq!(Solver::with_deadline(Instant::now()).try_solve(&game), None);
+    }
+
+    #[test]
+    fn solve_takes_an_immediate_win() {
+        let mut board = new_board();
+        board[0] = Some(0);
+        board[1] = Some(2);
+        board[2] = Some(4);
+        let game = GameState::new(board, 8, Player::Agent);
+        let solution = Solver::new().solve(&game);
+        assert_eq!(solution.outcome, Outcome::Win);
+        assert_eq!(solution.best_move.position, 3);
+    }
+
+    #[test]
+    fn solve_finds_a_draw_on_the_last_square() {
+        let mut board = draw_board();
+        let last_piece = board[15].take().unwrap();
+        let game = GameState::new(board, last_piece, Player::Agent);
+        let solution = Solver::new().solve(&game);
+        assert_eq!(solution.outcome, Outcome::Draw);
+        assert_eq!(solution.best_move.position, 15);
+    }
+
+    #[test]
+    fn solve_finds_the_only_move_that_does_not_lose() {
+        let board = [
+            Some(5),
+            None,
+            Some(13),
+            None,
+            Some(4),
+            Some(3),
+            Some(6),
+            Some(10),
+            Some(9),
+            Some(1),
+            Some(14),
+            Some(2),
+            Some(7),
+            Some(12),
+            None,
+            Some(15),
+        ];
+        let game = GameState::new(board, 8, Player::Agent);
+        let solution = Solver::new().solve(&game);
+        assert_eq!(solution.outcome, Outcome::Draw);
+        assert_eq!(solution.best_move.position, 3);
+        assert_eq!(solution.best_move.next_piece, 11);
+    }
+
+    #[test]
+    fn solve_finds_a_win_two_moves_ahead() {
+        let board = [
+            None,
+            Some(6),
+            Some(10),
+            Some(15),
+            Some(11),
+            Some(14),
+            None,
+            None,
+            Some(12),
+            Some(2),
+            None,
+            Some(1),
+            Some(8),
+            Some(9),
+            Some(3),
+            Some(4),
+        ];
+        let game = GameState::new(board, 0, Player::Agent);
+        let solution = Solver::new().solve(&game);
+        assert_eq!(solution.outcome, Outcome::Win);
+        assert!([6, 7].contains(&solution.best_move.position));
+        assert_eq!(solution.best_move.next_piece, 5);
+    }
+
+    #[test]
+    fn solve_proves_a_loss_when_every_move_loses() {
+        let board = [
+            Some(9),
+            None,
+            Some(2),
+            Some(13),
+            Some(15),
+            Some(8),
+            None,
+            Some(4),
+            Some(14),
+            None,
+            Some(6),
+            Some(0),
+            Some(5),
+            Some(10),
+            Some(12),
+            Some(7),
+        ];
+        let game = GameState::new(board, 3, Player::Agent);
+        let solution = Solver::new().solve(&game);
+        assert_eq!(solution.outcome, Outcome::Loss);
+    }
+
+    #[test]
+    fn solve_gives_the_same_outcome_with_a_warm_transposition_table() {
+        let board = [
+            None,
+            Some(8),
+            Some(2),
+            None,
+            Some(1),
+            Some(13),
+            Some(6),
+            Some(9),
+            Some(10),
+            Some(5),
+            None,
+            Some(14),
+            None,
+            Some(7),
+            Some(0),
+            None,
+        ];
+        let game = GameState::new(board, 3, Player::Agent);
+        let mut solver = Solver::new();
+        let first = solver.solve(&game);
+        let second = solver.solve(&game);
+        assert_eq!(first.outcome, second.outcome);
+        assert_eq!(first.outcome, Solver::new().solve(&game).outcome);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::game::Move;
+use std::sync::OnceLock;
+
+/* Quarto symmetries
+
+ - the board has 32 symmetries that map every row, column and diagonal onto
+   another one: the 8 rotations and reflections, and the swaps of rows and
+   columns that keep the diagonals intact, like turning the board inside out
+ - the pieces have 384: any reordering of the 4 attributes, combined with
+   flipping any of them
+ - two positions related by a board symmetry and a piece relabelling play out
+   the same, so a search only ever needs to see one of them
+
+*/
+const SIDE: usize = 4;
+const NUM_ATTRIBUTES: usize = 4;
+
+// A board symmetry together with a piece relabelling. Square i moves to
+// squares[i] and piece p becomes pieces[p].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    squares: [u8; 16],
+    pieces: [u8; 16],
+}
+
+impl Transform {
+    #[allow(dead_code)] // Only the tests look for it among all().
+    pub fn identity() -> Self {
+        let identity = std::array::from_fn(|i| i as u8);
+        Self {
+            squares: identity,
+            pieces: identity,
+        }
+    }
+
+    // Every combination of board symmetry and piece relabelling.
+    pub fn all() -> &'static [Transform] {
+        static TRANSFORMS: OnceLock<Vec<Transform>> = OnceLock::new();
+        TRANSFORMS.get_or_init(|| {
+            let relabellings = piece_relabellings();
+            board_symmetries()
+                .into_iter()
+                .flat_map(|squares| {
+                    relabellings
+                        .iter()
+                        .map(move |&pieces| Transform { squares, pieces })
+                })
+                .collect()
+        })
+    }
+
+    pub fn position(&self, position: i32) -> i32 {
+        self.squares[position as usize] as i32
+    }
+
+    pub fn piece(&self, piece: i32) -> i32 {
+        self.pieces[piece as usize] as i32
+    }
+
+    #[allow(dead_code)] // Searches never map a canonical move back out; the tests do.
+    pub fn apply_move(&self, the_move: &Move) -> Move {
+        Move {
+            position: self.position(the_move.position),
+            piece: self.piece(the_move.piece),
+            next_piece: self.piece(the_move.next_piece),
+        }
+    }
+
+    #[allow(dead_code)] // Searches never map a canonical move back out; the tests do.
+    pub fn inverse(&self) -> Self {
+        let mut inverse = *self;
+        for i in 0..16 {
+            inverse.squares[self.squares[i] as usize] = i as u8;
+            inverse.pieces[self.pieces[i] as usize] = i as u8;
+        }
+        inverse
+    }
+}
+
+// Rows are relabelled by one permutation and columns by either the same one or
+// its mirror image, optionally followed by a transpose. Only permutations that
+// commute with reversing the order keep the anti-diagonal a line.
+fn board_symmetries() -> Vec<[u8; 16]> {
+    let line_permutations: Vec<[usize; SIDE]> = permutations::<SIDE>()
+        .into_iter()
+        .filter(|p| (0..SIDE).all(|i| p[SIDE - 1 - i] == SIDE - 1 - p[i]))
+        .collect();
+
+    let mut symmetries = Vec::new();
+    for rows in &line_permutations {
+        for mirror_columns in [false, true] {
+            for transpose in [false, true] {
+                symmetries.push(std::array::from_fn(|square| {
+                    let (row, col) = (square / SIDE, square % SIDE);
+                    let new_row = rows[row];
+                    let new_col = if mirror_columns {
+                        SIDE - 1 - rows[col]
+                    } else {
+                        rows[col]
+                    };
+                    let (new_row, new_col) = if transpose {
+                        (new_col, new_row)
+                    } else {
+                        (new_row, new_col)
+                    };
+                    (new_row * SIDE + new_col) as u8
+                }));
+            }
+        }
+    }
+    symmetries
+}
+
+// Each attribute bit moves to a new place, then any of them can be flipped.
+fn piece_relabellings() -> Vec<[u8; 16]> {
+    let mut relabellings = Vec::new();
+    for attributes in permutations::<NUM_ATTRIBUTES>() {
+        for flips in 0..16u8 {
+            relabellings.push(std::array::from_fn(|piece| {
+                let mut relabelled = 0;
+                for (bit, &new_bit) in attributes.iter().enumerate() {
+                    relabelled |= ((piece as u8 >> bit) & 1) << new_bit;
+                }
+                relabelled ^ flips
+            }));
+        }
+    }
+    relabellings
+}
+
+fn permutations<const N: usize>() -> Vec<[usize; N]> {
+    let mut permutations = Vec::new();
+    let mut current: [usize; N] = std::array::from_fn(|i| i);
+    permute(&mut current, 0, &mut permutations);
+    permutations
+}
+
+fn permute<const N: usize>(current: &mut [usize; N], start: usize, out: &mut Vec<[usize; N]>) {
+    if start == N {
+        out.push(*current);
+        return;
+    }
+    for i in start..N {
+        current.swap(start, i);
+        permute(current, start + 1, out);
+        current.swap(start, i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::MATCH_POSITIONS;
+    use std::collections::HashSet;
+
+    fn line_set(line: impl Iterator<Item = usize>) -> Vec<usize> {
+        let mut line: Vec<usize> = line.collect();
+        line.sort();
+        line
+    }
+
+    #[test]
+    fn board_symmetries_are_32_distinct_permutations() {
+        let symmetries = board_symmetries();
+        let distinct: HashSet<[u8; 16]> = symmetries.iter().copied().collect();
+        assert_eq!(symmetries.len(), 32);
+        assert_eq!(distinct.len(), 32);
+    }
+
+    #[test]
+    fn board_symmetries_map_every_line_onto_a_line() {
+        let lines: HashSet<Vec<usize>> = MATCH_POSITIONS
+            .iter()
+            .map(|line| line_set(line.iter().copied()))
+            .collect();
+
+        for symmetry in board_symmetries() {
+            for line in MATCH_POSITIONS {
+                let moved = line_set(line.iter().map(|&sq| symmetry[sq] as usize));
+                assert!(lines.contains(&moved), "{:?} broke {:?}", symmetry, line);
+            }
+        }
+    }
+
+    #[test]
+    fn piece_relabellings_keep_shared_attributes_shared() {
+        let relabellings = piece_relabellings();
+        assert_eq!(relabellings.len(), 384);
+        for pieces in relabellings {
+            for a in 0..16usize {
+                for b in 0..16usize {
+                    let shared = (!(a ^ b) & 0b1111).count_ones();
+                    let relabelled = pieces[a] ^ pieces[b];
+                    assert_eq!((!relabelled & 0b1111).count_ones(), shared);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn inverse_undoes_the_transform() {
+        let transform = Transform::all()[1234];
+        let inverse = transform.inverse();
+        for i in 0..16 {
+            assert_eq!(inverse.position(transform.position(i)), i);
+            assert_eq!(inverse.piece(transform.piece(i)), i);
+        }
+    }
+
+    #[test]
+    fn all_starts_with_the_identity() {
+        assert_eq!(Transform::all().len(), 32 * 384);
+        assert_eq!(Transform::all()[0], Transform::identity());
+    }
+}
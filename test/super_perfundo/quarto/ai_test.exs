@@ -1,27 +1,241 @@
 defmodule SuperPerfundo.Quarto.AITest do
   use ExUnit.Case
-  alias SuperPerfundo.Quarto.AI
+  alias SuperPerfundo.Quarto.{AI, Board}
 
-  describe "choose_position_and_next_piece/2" do
+  describe "choose_position_and_next_piece/4" do
     test "an index of the board is returned" do
       board = {nil, nil, 8, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil}
-      {position, _piece} = AI.choose_position_and_next_piece(board, 10)
+      {:ok, {position, _piece}} = AI.choose_position_and_next_piece(board, 10)
       assert position >= 0 && position < tuple_size(board)
       refute position == 2
     end
 
     test "an integer representing another piece is returned" do
-      board = {nil, 1, nil, 5}
-      {_position, piece} = AI.choose_position_and_next_piece(board, 10)
+      board = {nil, 1, nil, 5, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil}
+      {:ok, {_position, piece}} = AI.choose_position_and_next_piece(board, 10)
       assert piece >= 0 && piece < 16
       refute piece == 10
     end
+
+    test "a seed is accepted" do
+      board = {nil, 1, nil, 5, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil}
+      {:ok, {position, _piece}} = AI.choose_position_and_next_piece(board, 10, :expert, 1234)
+      assert is_nil(elem(board, position))
+    end
+
+    test "every difficulty chooses a legal move" do
+      board = {nil, 1, nil, 5, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil}
+
+      for difficulty <- [:beginner, :intermediate, :expert, :perfect] do
+        {:ok, {position, piece}} = AI.choose_position_and_next_piece(board, 10, difficulty)
+        assert is_nil(elem(board, position))
+        refute piece in [1, 5, 10]
+      end
+    end
+
+    test "an unknown difficulty is rejected" do
+      assert_raise ArgumentError, fn ->
+        AI.choose_position_and_next_piece(Board.new(), 0, :impossible)
+      end
+    end
+
+    test "a seed that isn't a non-negative integer is rejected" do
+      assert_raise ArgumentError, fn ->
+        AI.choose_position_and_next_piece(Board.new(), 0, :expert, -1)
+      end
+    end
+
+    test "a board that isn't a tuple is an error" do
+      assert AI.choose_position_and_next_piece([nil, 1], 10) == {:error, :not_a_tuple}
+    end
+
+    test "a board without 16 positions is an error" do
+      assert AI.choose_position_and_next_piece({nil, 1, nil, 5}, 10) ==
+               {:error, :wrong_board_size}
+    end
+
+    test "a position that isn't a piece is an error" do
+      board = put_elem(Board.new(), 3, 16)
+      assert AI.choose_position_and_next_piece(board, 10) == {:error, :invalid_piece}
+
+      board = put_elem(Board.new(), 3, :oops)
+      assert AI.choose_position_and_next_piece(board, 10) == {:error, :invalid_piece}
+    end
+
+    test "a piece played twice is an error" do
+      board = Board.new() |> put_elem(3, 4) |> put_elem(7, 4)
+      assert AI.choose_position_and_next_piece(board, 10) == {:error, :duplicate_piece}
+    end
+
+    test "an active piece that doesn't exist is an error" do
+      assert AI.choose_position_and_next_piece(Board.new(), 16) ==
+               {:error, :invalid_active_piece}
+
+      assert AI.choose_position_and_next_piece(Board.new(), nil) ==
+               {:error, :invalid_active_piece}
+    end
+
+    test "an active piece already on the board is an error" do
+      board = put_elem(Board.new(), 3, 10)
+
+      assert AI.choose_position_and_next_piece(board, 10) ==
+               {:error, :active_piece_on_board}
+    end
+
+    test "a board that is already won is an error" do
+      board = {0, 2, 4, 8, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil}
+      assert AI.choose_position_and_next_piece(board, 10) == {:error, :game_over}
+    end
+  end
+
+  describe "choose_position_and_next_piece_in_session/5" do
+    test "moves are chosen across turns of the same game" do
+      session = AI.new_session()
+      board = put_elem(Board.new(), 2, 8)
+
+      {:ok, {position, piece}} = AI.choose_position_and_next_piece_in_session(session, board, 10)
+      board = put_elem(board, position, 10)
+
+      reply = Enum.find(0..15, &is_nil(elem(board, &1)))
+      board = put_elem(board, reply, piece)
+      next_piece = Enum.find(0..15, &(&1 not in Tuple.to_list(board) and &1 != piece))
+
+      assert {:ok, {position, _}} =
+               AI.choose_position_and_next_piece_in_session(session, board, next_piece)
+
+      assert is_nil(elem(board, position))
+    end
+
+    test "bad boards are still errors" do
+      assert AI.choose_position_and_next_piece_in_session(AI.new_session(), {nil}, 10) ==
+               {:error, :wrong_board_size}
+    end
+  end
+
+  describe "search/4" do
+    test "the report describes the chosen move and the search behind it" do
+      board = {nil, 1, nil, 5, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil}
+      {:ok, report} = AI.search(board, 10, :expert, 1234)
+
+      assert is_nil(elem(board, report.position))
+      assert report.seed == 1234
+      assert report.rounds > 0
+      assert report.tree_size > 0
+      assert is_nil(report.outcome)
+
+      assert [%{player: :ai, position: position, piece: 10, next_piece: next_piece} | rest] =
+               report.principal_variation
+
+      assert Enum.all?(Enum.take(rest, 1), &(&1.player == :user))
+
+      assert {position, next_piece} == {report.position, report.next_piece}
+
+      for child <- report.children do
+        assert_in_delta child.win + child.draw + child.loss, 1.0, 1.0e-9
+      end
+    end
+
+    test "a solved game reports its outcome" do
+      board = {0, 2, 4, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil}
+      assert {:ok, %{position: 3, outcome: :win}} = AI.search(board, 8)
+    end
+
+    test "bad boards are still errors" do
+      assert AI.search({nil}, 10) == {:error, :wrong_board_size}
+    end
+  end
+
+  describe "search_in_session/5" do
+    test "the report comes from the session's search" do
+      {:ok, report} = AI.search_in_session(AI.new_session(), Board.new(), 0)
+      assert is_nil(elem(Board.new(), report.position))
+      assert report.children != []
+    end
+  end
+
+  describe "suggest_move/4" do
+    test "the best moves for the player are suggested" do
+      board = {nil, 1, nil, 5, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil}
+      {:ok, candidates} = AI.suggest_move(board, 10, 2)
+
+      assert length(candidates) == 2
+
+      for candidate <- candidates do
+        assert is_nil(elem(board, candidate.position))
+        assert candidate.piece == 10
+        refute candidate.next_piece in [1, 5, 10]
+      end
+    end
+
+    test "hints can play at the game's difficulty" do
+      board = {nil, 1, nil, 5, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil}
+      {:ok, candidates} = AI.suggest_move(board, 10, 2, :beginner)
+
+      assert length(candidates) == 2
+      assert Enum.all?(candidates, &is_nil(elem(board, &1.position)))
+    end
+
+    test "a winning move is the only suggestion, however many are asked for" do
+      board = {0, 2, 4, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil}
+      assert {:ok, [%{position: 3, outcome: :win}]} = AI.suggest_move(board, 8, 3)
+    end
+
+    test "bad boards are still errors" do
+      assert AI.suggest_move({nil}, 10) == {:error, :wrong_board_size}
+    end
   end
 
-  describe "choose_next_piece/0" do
-    test "a piece integer is randomly chosen" do
-      piece = AI.choose_next_piece()
+  describe "scheduling" do
+    test "searches don't block the normal schedulers" do
+      ticker = spawn_link(fn -> measure_tick_gaps(System.monotonic_time(:millisecond), 0) end)
+
+      # One search per normal scheduler. If the NIF ran on them, every scheduler
+      # would be stuck in Rust and the ticker couldn't run until the searches end.
+      started_at = System.monotonic_time(:millisecond)
+
+      1..System.schedulers_online()
+      |> Enum.map(fn _ ->
+        Task.async(fn -> AI.choose_position_and_next_piece(Board.new(), 0, :expert) end)
+      end)
+      |> Task.await_many(30_000)
+      |> Enum.each(fn result -> assert {:ok, _} = result end)
+
+      searched_for = System.monotonic_time(:millisecond) - started_at
+      send(ticker, {:stop, self()})
+      assert_receive {:max_gap, max_gap}
+
+      # A blocked scheduler would leave a gap about as long as the searches
+      # themselves, not a small fraction of it.
+      assert max_gap < 50
+      assert max_gap * 4 < searched_for
+    end
+  end
+
+  describe "choose_piece/2" do
+    test "any piece can open the game" do
+      {:ok, piece} = AI.choose_piece(Board.new())
       assert piece >= 0 && piece < 16
     end
+
+    test "a piece that wins on the spot is not handed over" do
+      board = {0, 2, 4, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil, nil}
+      {:ok, piece} = AI.choose_piece(board)
+      assert piece in [9, 11, 13, 15]
+    end
+
+    test "a board with nothing left to give is an error" do
+      board = List.to_tuple([7, 8, 5, 10, 12, 3, 14, 1, 15, 13, 9, 6, 2, 11, 4, 0])
+      assert AI.choose_piece(board) == {:error, :game_over}
+    end
+  end
+
+  defp measure_tick_gaps(last_tick, max_gap) do
+    receive do
+      {:stop, from} -> send(from, {:max_gap, max_gap})
+    after
+      5 ->
+        now = System.monotonic_time(:millisecond)
+        measure_tick_gaps(now, max(max_gap, now - last_tick))
+    end
   end
 end